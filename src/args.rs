@@ -0,0 +1,122 @@
+use crate::display::Backend;
+use crate::config::Config;
+use crate::log::{Level, Target};
+
+pub struct Args {
+    pub help: bool,
+    pub list_devices: bool,
+    pub device: Option<String>,
+    pub fonts: Vec<String>,
+    pub config: Option<String>,
+    pub cols: u32,
+    pub rows: u32,
+    pub brightness: u8,
+    pub backend: Backend,
+    pub include_clock_ticks: bool,
+    pub log_level: Level,
+    pub log_target: Target
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            help: false,
+            list_devices: false,
+            device: None,
+            fonts: Vec::new(),
+            config: None,
+            cols: 32,
+            rows: 16,
+            brightness: 100,
+            backend: Backend::Led,
+            include_clock_ticks: true,
+            log_level: Level::Info,
+            log_target: Target::Stderr
+        }
+    }
+}
+
+impl Args {
+    pub const USAGE: &'static str = "Usage: midi-panel-meter [OPTIONS]
+
+Reads MIDI IN and displays the current status of pedals and notes on a panel meter.
+
+Options:
+  -d, --device PATH|NAME   MIDI device to read, either a path (eg. /dev/midi1) or part
+                           of its name (default: the first /dev/midi* found)
+  -l, --list-devices       List available MIDI devices and exit
+  -f, --font PATH          Load an additional BDF font, which is used for text when it
+                           is the largest that fits (can be given multiple times,
+                           default: the built in 6x9 font only)
+  -c, --config PATH        Config file (default: midi-panel-meter.conf, if it exists)
+  -s, --size COLSxROWS     Size of the panel in pixels (default: 32x16)
+  -b, --brightness 1-100   Panel brightness percentage (default: 100)
+      --backend led|terminal
+                           Display on the led panel, or in the terminal for testing
+                           without one (default: led)
+      --no-clock           Ignore MIDI clock ticks (hides the beat flash and tempo)
+      --log stderr|journal|PATH
+                           Where to write log messages, either stderr with timestamps,
+                           stderr formatted for the systemd journal, or appended to a
+                           file (default: stderr)
+      --log-level error|warn|info|debug
+                           Minimum level of log messages to write (default: info)
+  -v, --verbose            Log at debug level, including each MIDI message received
+  -h, --help               Show this help and exit";
+
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let mut args = args.skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => parsed.help = true,
+                "-l" | "--list-devices" => parsed.list_devices = true,
+                "-d" | "--device" => parsed.device = Some(Self::value(&arg, args.next())?),
+                "-f" | "--font" => parsed.fonts.push(Self::value(&arg, args.next())?),
+                "-c" | "--config" => parsed.config = Some(Self::value(&arg, args.next())?),
+                "-s" | "--size" => {
+                    let size = Self::value(&arg, args.next())?;
+                    let (cols, rows) = size.split_once('x')
+                        .and_then(|(c, r)| Some((c.parse().ok()?, r.parse().ok()?)))
+                        .filter(|(c, r)| *c > 0 && *r > 0)
+                        .ok_or_else(|| format!("Invalid panel size '{}' (expected COLSxROWS, eg. 32x16)", size))?;
+                    parsed.cols = cols;
+                    parsed.rows = rows;
+                },
+                "-b" | "--brightness" => {
+                    let brightness = Self::value(&arg, args.next())?;
+                    parsed.brightness = match brightness.parse() {
+                        Ok(b) if (1..=100).contains(&b) => b,
+                        _ => return Err(format!("Invalid brightness '{}' (expected 1-100)", brightness))
+                    };
+                },
+                "--backend" => {
+                    let backend = Self::value(&arg, args.next())?;
+                    parsed.backend = match backend.as_str() {
+                        "led" => Backend::Led,
+                        "terminal" => Backend::Terminal,
+                        _ => return Err(format!("Invalid backend '{}' (expected 'led' or 'terminal')", backend))
+                    };
+                },
+                "--no-clock" => parsed.include_clock_ticks = false,
+                "--log" => parsed.log_target = Target::parse(&Self::value(&arg, args.next())?),
+                "--log-level" => {
+                    let level = Self::value(&arg, args.next())?;
+                    parsed.log_level = Level::parse(&level)
+                        .ok_or_else(|| format!("Invalid log level '{}' (expected 'error', 'warn', 'info' or 'debug')", level))?;
+                },
+                "-v" | "--verbose" => parsed.log_level = Level::Debug,
+                _ => return Err(format!("Unknown option '{}'", arg))
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn value(arg: &str, value: Option<String>) -> Result<String, String> {
+        value.ok_or_else(|| format!("Missing value for '{}'", arg))
+    }
+
+    pub fn config_path(&self) -> &str {
+        self.config.as_deref().unwrap_or(Config::DEFAULT_PATH)
+    }
+}
@@ -0,0 +1,47 @@
+use std::time::{Duration, Instant};
+use rpi_led_matrix::LedColor;
+
+use crate::config::Align;
+use crate::display::Canvas;
+use crate::font::{Fonts, Scroll};
+
+// text pushed to the panel (eg. "NEXT: Song 4"), shown over the meter until it expires or is replaced
+pub struct Banner {
+    text: String,
+    until: Option<Instant>,
+    scroll: Scroll
+}
+
+impl Banner {
+    const COLOR: LedColor = LedColor { red: 255, green: 255, blue: 255 };
+
+    pub fn new(text: String, duration: Option<Duration>) -> Self {
+        Self {
+            text,
+            until: duration.map(|d| Instant::now() + d),
+            scroll: Scroll::new()
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.until.is_some_and(|u| Instant::now() >= u)
+    }
+
+    // true if the text has scrolled since it was last checked
+    pub fn animate(&mut self) -> bool {
+        self.scroll.animate()
+    }
+
+    // time until the text scrolls or expires
+    pub fn next_animation(&self) -> Option<Duration> {
+        let expires = self.until.map(|u| u.saturating_duration_since(Instant::now()));
+        [self.scroll.next_animation(), expires].into_iter().flatten().min()
+    }
+
+    pub fn draw(&self, canvas: &mut dyn Canvas, fonts: &Fonts) {
+        let (width, height) = canvas.size();
+        let font = fonts.fitting(&self.text, width, height);
+        let scrolling = self.scroll.draw_line(canvas, font, &self.text, 0..height, Align::Center, &Self::COLOR);
+        self.scroll.set_scrolling(scrolling);
+    }
+}
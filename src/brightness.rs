@@ -0,0 +1,85 @@
+use chrono::{Local, NaiveTime};
+use wmidi::{Channel, ControlFunction, MidiMessage};
+
+use crate::config::Config;
+use crate::sysex::Command;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct NightSchedule {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub brightness: u8
+}
+
+impl NightSchedule {
+    pub fn is_night(&self, now: NaiveTime) -> bool {
+        if self.start <= self.end {
+            now >= self.start && now < self.end
+        } else {
+            // overnight, eg. 22:00-07:00
+            now >= self.start || now < self.end
+        }
+    }
+}
+
+pub struct Brightness {
+    level: u8,
+    night: Option<NightSchedule>,
+    control: Option<(Option<Channel>, ControlFunction)>,
+    controlled: Option<u8>
+}
+
+impl Brightness {
+    pub fn new(config: &Config, default: u8) -> Self {
+        let mut brightness = Self {
+            level: default,
+            night: None,
+            control: None,
+            controlled: None
+        };
+        brightness.reconfigure(config, default);
+        brightness
+    }
+
+    // apply a changed config, keeping any level set by CC or SysEx unless the brightness or CC is changed
+    pub fn reconfigure(&mut self, config: &Config, default: u8) {
+        let level = config.brightness.unwrap_or(default);
+        if level != self.level || (self.control.is_some() && config.brightness_cc.is_none()) {
+            self.controlled = None;
+        }
+        self.level = level;
+        self.night = config.night.clone();
+        self.control = config.brightness_cc;
+    }
+
+    // if the message is the brightness CC or SysEx command, set the brightness from it and return true
+    pub fn handle(&mut self, message: &MidiMessage) -> bool {
+        match (self.control, message) {
+            (Some((channel, cc)), MidiMessage::ControlChange(ch, message_cc, v)) if cc == *message_cc && channel.is_none_or(|c| c == *ch) => {
+                let v: u8 = (*v).into();
+                self.controlled = Some((v as usize * 100 / 127) as u8);
+                true
+            },
+            (_, MidiMessage::OwnedSysEx(_)) => match Command::parse(message) {
+                Some(Command::Brightness(brightness)) => {
+                    self.controlled = Some(brightness);
+                    true
+                },
+                _ => false
+            },
+            _ => false
+        }
+    }
+
+    pub fn meter(&self) -> u8 {
+        self.controlled.unwrap_or(self.level)
+    }
+
+    // the clock is dimmed (or blanked) at night, when no one is playing
+    pub fn clock(&self) -> u8 {
+        match &self.night {
+            Some(night) if night.is_night(Local::now().time()) => self.meter().min(night.brightness),
+            _ => self.meter()
+        }
+    }
+}
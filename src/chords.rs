@@ -0,0 +1,120 @@
+use rpi_led_matrix::LedColor;
+
+use crate::helper::scale;
+use crate::notes::NoteSlot;
+use crate::display::Canvas;
+use crate::font::Fonts;
+
+pub struct PitchClasses<const C: usize> {
+    velocities: [[u8; 12]; C],
+    bass: Option<usize>
+}
+
+impl<const C: usize> PitchClasses<C> {
+    const NAMES: [&'static str; 12] = ["C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B"];
+
+    // intervals above the root (as a bit mask) and the suffix for that chord, in order of preference
+    const CHORDS: [(u16, &'static str); 21] = [
+        (0b000010010001, ""),
+        (0b000010001001, "m"),
+        (0b000001001001, "dim"),
+        (0b000100010001, "+"),
+        (0b000010000101, "sus2"),
+        (0b000010100001, "sus4"),
+        (0b010010010001, "7"),
+        (0b100010010001, "M7"),
+        (0b010010001001, "m7"),
+        (0b100010001001, "mM7"),
+        (0b001001001001, "dim7"),
+        (0b010001001001, "m7b5"),
+        (0b001010010001, "6"),
+        (0b001010001001, "m6"),
+        (0b010010010101, "9"),
+        (0b000010010101, "add9"),
+        (0b010010100001, "7sus4"),
+        (0b010000010001, "7"),
+        (0b100000010001, "M7"),
+        (0b010000001001, "m7"),
+        (0b000010000001, "5")
+    ];
+
+    pub fn new<'a>(slots: impl Iterator<Item = &'a NoteSlot<C>>) -> Self {
+        let mut velocities = [[0; 12]; C];
+        let mut bass = None;
+        for slot in slots {
+            let pitch_class = slot.note as usize % 12;
+            for (velocity, v) in velocities.iter_mut().zip(slot.channels) {
+                let v: u8 = v.into();
+                if v > velocity[pitch_class] {
+                    velocity[pitch_class] = v;
+                }
+            }
+            if bass.is_none() && !slot.is_empty() {
+                // slots are ordered by pitch, so the first is the lowest
+                bass = Some(pitch_class);
+            }
+        }
+        Self {
+            velocities,
+            bass
+        }
+    }
+
+    pub fn chord_name(&self) -> Option<String> {
+        let mut held: u16 = 0;
+        for c in 0..C {
+            for pc in 0..12 {
+                if self.velocities[c][pc] > 0 {
+                    held |= 1 << pc;
+                }
+            }
+        }
+        let bass = self.bass?;
+        if held.count_ones() == 1 {
+            return Some(Self::NAMES[bass].to_string());
+        }
+        // prefer the bass note as the root, otherwise try each other note
+        for root in (0..12).map(|i| (bass + i) % 12) {
+            if held & (1 << root) != 0 {
+                let relative = ((held >> root) | (held << (12 - root))) & 0xFFF;
+                for (intervals, suffix) in Self::CHORDS {
+                    if relative == intervals {
+                        return Some(format!("{}{}", Self::NAMES[root], suffix));
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    pub fn draw(&self, canvas: &mut dyn Canvas, first_column: i32, width: usize, colors: &[LedColor; C], fonts: Option<&Fonts>) {
+        let column_width = (width as i32 / 12).max(1);
+        let height = canvas.size().1;
+        let top = if let Some(fonts) = fonts {
+            let name = self.chord_name().unwrap_or_default();
+            // chord name in the top half, centred above the columns
+            let font = fonts.fitting(&name, 12 * column_width, height / 2);
+            let x = first_column + (12 * column_width - font.width(&name)) / 2;
+            font.draw(canvas, &name, x, font.ascent(), &Self::TEXT);
+            font.height() + 1
+        } else {
+            1
+        };
+        let rows_per_channel = (height - top) / C as i32;
+        for (c, channel_color) in colors.iter().enumerate() {
+            let bottom = height - 1 - c as i32 * rows_per_channel;
+            for pc in 0..12 {
+                let v = self.velocities[c][pc];
+                if v > 0 {
+                    let color = scale(channel_color, v * 2 + 1);
+                    for x in 0..column_width {
+                        let x = first_column + pc as i32 * column_width + x;
+                        canvas.draw_line(x, bottom - rows_per_channel + 1, x, bottom, &color);
+                    }
+                }
+            }
+        }
+    }
+
+    const TEXT: LedColor = LedColor { red: 255, green: 255, blue: 255 };
+}
@@ -0,0 +1,121 @@
+use std::time::Duration;
+use chrono::{DateTime, Local, Timelike};
+
+use crate::config::ClockConfig;
+use crate::display::Canvas;
+use crate::font::{Fonts, Scroll};
+use crate::idle;
+
+pub struct Clock {
+    config: ClockConfig,
+    shown: Option<u32>,
+    scroll: Scroll
+}
+
+impl Clock {
+    pub fn new(config: &ClockConfig) -> Self {
+        Self {
+            config: config.clone(),
+            shown: None,
+            scroll: Scroll::new()
+        }
+    }
+
+    pub fn reconfigure(&mut self, config: &ClockConfig) {
+        self.config = config.clone();
+        self.shown = None;
+    }
+
+    // true if the time has moved on (or text has scrolled) since it was last checked
+    pub fn animate(&mut self) -> bool {
+        let now = Some(Local::now().num_seconds_from_midnight());
+        let changed = self.shown != now;
+        self.shown = now;
+        self.scroll.animate() | changed
+    }
+
+    pub fn next_animation(&self) -> Duration {
+        self.scroll.next_animation().unwrap_or_else(|| {
+            Duration::from_nanos(1_000_000_000 - Local::now().nanosecond() as u64 % 1_000_000_000)
+        })
+    }
+
+    fn time(&self, now: &DateTime<Local>) -> String {
+        let format = if self.config.twelve_hour {
+            twelve_hour(&self.config.format)
+        } else {
+            self.config.format.clone()
+        };
+        let time = now.format(&format).to_string();
+        // blink the colon every second
+        if self.config.blink && !now.second().is_multiple_of(2) {
+            time.replace(':', " ")
+        } else {
+            time
+        }
+    }
+
+    pub fn draw(&self, canvas: &mut dyn Canvas, fonts: &Fonts) {
+        let now = Local::now();
+        let time = self.time(&now);
+        let (width, height) = canvas.size();
+        let (align, color) = (self.config.align, &self.config.color);
+        let mut scrolling = false;
+        // the date only goes underneath on panels tall enough for both lines
+        let date = self.config.date.as_ref().map(|f| now.format(f).to_string());
+        match date.map(|d| (fonts.fitting(&d, width, height / 2), d)) {
+            Some((date_font, date)) if date_font.height() <= height / 2 => {
+                let time_height = height - date_font.height();
+                scrolling |= self.scroll.draw_line(canvas, fonts.fitting(&time, width, time_height), &time, 0..time_height, align, color);
+                scrolling |= self.scroll.draw_line(canvas, date_font, &date, time_height..height, align, color);
+            },
+            _ => {
+                scrolling |= self.scroll.draw_line(canvas, fonts.fitting(&time, width, height), &time, 0..height, align, color);
+            }
+        }
+        self.scroll.set_scrolling(scrolling);
+    }
+
+    // draw just the time in a position which moves slowly around the panel, to avoid burn-in
+    pub fn draw_shifted(&self, canvas: &mut dyn Canvas, fonts: &Fonts) {
+        let time = self.time(&Local::now());
+        let (width, height) = canvas.size();
+        let font = fonts.fitting(&time, width, height);
+        let (x, y) = idle::shift(width - font.width(&time), height - font.height());
+        font.draw(canvas, &time, x, y + font.ascent(), &self.config.color);
+        self.scroll.set_scrolling(false);
+    }
+}
+
+// the format with each 24 hour code changed to show 12 hour time instead (without a leading zero, like most clocks)
+fn twelve_hour(format: &str) -> String {
+    let mut result = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        // any padding flag (eg. %-H) is replaced, as the hour is never padded
+        let mut code = chars.next();
+        let flag = code.filter(|f| ['-', '_', '0'].contains(f));
+        if flag.is_some() {
+            code = chars.next();
+        }
+        match code {
+            Some('H') | Some('k') => result.push_str("%-I"),
+            Some('R') => result.push_str("%-I:%M"),
+            Some('T') => result.push_str("%-I:%M:%S"),
+            Some(code) => {
+                result.push('%');
+                result.extend(flag);
+                result.push(code);
+            },
+            None => {
+                result.push('%');
+                result.extend(flag);
+            }
+        }
+    }
+    result
+}
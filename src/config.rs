@@ -0,0 +1,518 @@
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+use chrono::NaiveTime;
+use chrono::format::{Item, StrftimeItems};
+use rpi_led_matrix::LedColor;
+use wmidi::{Note, Channel, ControlFunction, U7};
+
+use crate::meter::PanelMeter;
+use crate::screen::{Screen, ScreenTrigger};
+use crate::notes::{Eviction, NoteMapping};
+use crate::drums::DrumPad;
+use crate::brightness::NightSchedule;
+use crate::idle::IdleMode;
+use crate::mpe::MpeConfig;
+use crate::error::{Error, Result};
+
+pub struct Config {
+    pub screens: Vec<Screen>,
+    pub screen_triggers: Vec<ScreenTrigger>,
+    pub chord_names: bool,
+    pub brightness: Option<u8>,
+    pub night: Option<NightSchedule>,
+    pub brightness_cc: Option<(Option<Channel>, ControlFunction)>,
+    pub idle: Option<(Duration, IdleMode)>,
+    pub max_hold: Option<Duration>,
+    pub eviction: Eviction,
+    pub clock: ClockConfig,
+    pub thru: ThruConfig,
+    pub channels: [ChannelConfig; PanelMeter::MIDI_CHANNELS]
+}
+
+#[derive(Clone)]
+pub struct ClockConfig {
+    pub format: String,
+    pub twelve_hour: bool,
+    pub blink: bool,
+    pub date: Option<String>,
+    pub color: LedColor,
+    pub align: Align
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Align {
+    Left, Center, Right
+}
+
+#[derive(Clone, PartialEq)]
+pub struct ThruConfig {
+    pub device: Option<String>,
+    pub channels: Option<Vec<Channel>>,
+    pub remap: Vec<(Channel, Channel)>,
+    pub clock: bool,
+    pub sysex: bool
+}
+
+#[derive(Clone)]
+pub struct ChannelConfig {
+    pub midi_channel: Channel,
+    pub color: LedColor,
+    pub expression_cc: ControlFunction,
+    pub damper_cc: ControlFunction,
+    pub notes: NoteMapping,
+    pub drums: Option<Vec<DrumPad>>,
+    pub mpe: MpeConfig
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            screens: vec![Screen::Meter],
+            screen_triggers: Vec::new(),
+            chord_names: true,
+            brightness: None,
+            night: None,
+            brightness_cc: None,
+            idle: None,
+            max_hold: None,
+            eviction: Eviction::Damper,
+            clock: ClockConfig::default(),
+            thru: ThruConfig::default(),
+            channels: [
+                ChannelConfig::new(Channel::Ch1, LedColor { red: 255, green: 0, blue: 0 }),
+                ChannelConfig::new(Channel::Ch2, LedColor { red: 0, green: 255, blue: 0 }),
+                ChannelConfig::new(Channel::Ch3, LedColor { red: 0, green: 0, blue: 255 })
+            ]
+        }
+    }
+}
+
+impl Config {
+    pub const DEFAULT_PATH: &'static str = "midi-panel-meter.conf";
+
+    pub fn load(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| Error::Config(format!("Cannot read '{}': {}", path, e)))?;
+        let mut config = Self::default();
+        let mut section = String::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                // skip blank lines and comments
+            } else if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..(line.len() - 1)].trim().to_lowercase();
+            } else if let Some((key, value)) = line.split_once('=') {
+                config.set(&section, key.trim(), value.trim()).map_err(|e| Error::Config(format!("'{}' line {}: {}", path, i + 1, e)))?;
+            } else {
+                return Err(Error::Config(format!("'{}' line {}: Expected 'key = value'", path, i + 1)));
+            }
+        }
+        // each MIDI channel can only be shown once, as notes are looked up by channel
+        for (i, channel) in config.channels.iter().enumerate() {
+            if let Some(j) = config.channels[..i].iter().position(|c| c.midi_channel == channel.midi_channel) {
+                return Err(Error::Config(format!("'{}': [channel {}] and [channel {}] both have midi_channel {}", path, j + 1, i + 1, channel.midi_channel.number())));
+            }
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: &str) -> Result<(), String> {
+        if section == "display" {
+            match key {
+                "screens" => self.screens = parse_screens(value)?,
+                "trigger" => self.screen_triggers.push(parse_trigger(value)?),
+                "chord_names" => self.chord_names = parse_bool(value)?,
+                "brightness" => self.brightness = Some(parse_brightness(value)?),
+                "night" => self.night = parse_night(value)?,
+                "brightness_cc" => self.brightness_cc = parse_brightness_cc(value)?,
+                "idle" => self.idle = parse_idle(value)?,
+                "max_hold" => self.max_hold = parse_max_hold(value)?,
+                "eviction" => self.eviction = parse_eviction(value)?,
+                _ => return Err(format!("Unknown display setting '{}'", key))
+            }
+            Ok(())
+        } else if section == "clock" {
+            self.clock.set(key, value)
+        } else if section == "thru" {
+            self.thru.set(key, value)
+        } else if let Some(number) = section.strip_prefix("channel ") {
+            let c: usize = number.trim().parse().map_err(|_| format!("Invalid channel '{}'", number))?;
+            if c < 1 || c > self.channels.len() {
+                return Err(format!("Channel must be between 1 and {}", self.channels.len()));
+            }
+            self.channels[c - 1].set(key, value)
+        } else {
+            Err(format!("Unknown section '{}'", section))
+        }
+    }
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            format: "%H:%M".to_string(),
+            twelve_hour: false,
+            blink: true,
+            date: None,
+            color: LedColor { red: 255, green: 255, blue: 255 },
+            align: Align::Center
+        }
+    }
+}
+
+impl ClockConfig {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "format" => self.format = parse_format(value)?,
+            "hours" => self.twelve_hour = match value {
+                "12" => true,
+                "24" => false,
+                _ => return Err(format!("Invalid hours '{}' (expected '12' or '24')", value))
+            },
+            "blink" => self.blink = parse_bool(value)?,
+            "date" => self.date = if value == "off" { None } else { Some(parse_format(value)?) },
+            "color" => self.color = parse_color(value)?,
+            "align" => self.align = match value {
+                "left" => Align::Left,
+                "center" => Align::Center,
+                "right" => Align::Right,
+                _ => return Err(format!("Invalid align '{}' (expected 'left', 'center' or 'right')", value))
+            },
+            _ => return Err(format!("Unknown clock setting '{}'", key))
+        }
+        Ok(())
+    }
+}
+
+impl Default for ThruConfig {
+    fn default() -> Self {
+        Self {
+            device: None,
+            channels: None,
+            remap: Vec::new(),
+            clock: true,
+            sysex: true
+        }
+    }
+}
+
+impl ThruConfig {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "device" => self.device = if value == "off" { None } else { Some(value.to_string()) },
+            "channels" => self.channels = if value == "all" {
+                None
+            } else {
+                Some(value.split_whitespace().map(parse_channel).collect::<Result<_, _>>()?)
+            },
+            "remap" => self.remap = parse_remap(value)?,
+            "clock" => self.clock = parse_bool(value)?,
+            "sysex" => self.sysex = parse_bool(value)?,
+            _ => return Err(format!("Unknown thru setting '{}'", key))
+        }
+        Ok(())
+    }
+}
+
+// notices changes to the config file by polling when it was last modified
+pub struct ConfigWatcher {
+    path: String,
+    modified: Option<SystemTime>,
+    last_check: Instant
+}
+
+impl ConfigWatcher {
+    const CHECK_MS: u128 = 1000;
+
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            modified: Self::modified(path),
+            last_check: Instant::now()
+        }
+    }
+
+    fn modified(path: &str) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    // true if the file has been created, changed or deleted since last checked
+    pub fn changed(&mut self) -> bool {
+        if self.last_check.elapsed().as_millis() < Self::CHECK_MS {
+            return false;
+        }
+        self.last_check = Instant::now();
+        let modified = Self::modified(&self.path);
+        let changed = modified != self.modified;
+        self.modified = modified;
+        changed
+    }
+}
+
+impl ChannelConfig {
+    fn new(midi_channel: Channel, color: LedColor) -> Self {
+        Self {
+            midi_channel,
+            color,
+            expression_cc: ControlFunction::EXPRESSION_CONTROLLER,
+            damper_cc: ControlFunction::DAMPER_PEDAL,
+            notes: NoteMapping::PIANO,
+            drums: None,
+            mpe: MpeConfig::Off
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "midi_channel" => self.midi_channel = parse_channel(value)?,
+            "color" => self.color = parse_color(value)?,
+            "expression_cc" => self.expression_cc = parse_cc(value)?,
+            "damper_cc" => self.damper_cc = parse_cc(value)?,
+            "notes" => self.notes = parse_mapping(value)?,
+            "drums" => self.drums = parse_drums(value)?,
+            "mpe" => self.mpe = parse_mpe(value)?,
+            _ => return Err(format!("Unknown channel setting '{}'", key))
+        }
+        Ok(())
+    }
+}
+
+fn parse_screens(value: &str) -> Result<Vec<Screen>, String> {
+    let mut screens = Vec::new();
+    for name in value.split(',').map(|n| n.trim()) {
+        match Screen::ALL.iter().find(|s| s.name() == name) {
+            Some(screen) => screens.push(*screen),
+            None => return Err(format!("Invalid screen '{}' (expected {})", name, Screen::ALL.map(|s| s.name()).join(", ")))
+        }
+    }
+    Ok(screens)
+}
+
+fn parse_trigger(value: &str) -> Result<ScreenTrigger, String> {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    match words.as_slice() {
+        ["cc", channel, cc] => Ok(ScreenTrigger::ControlChange(parse_any_channel(channel)?, parse_cc(cc)?)),
+        ["program", channel] => Ok(ScreenTrigger::ProgramChange(parse_any_channel(channel)?)),
+        ["sysex", bytes @ ..] if !bytes.is_empty() => {
+            let prefix = bytes.iter()
+                .map(|b| u8::from_str_radix(b, 16).ok().and_then(|b| U7::try_from(b).ok()).ok_or_else(|| format!("Invalid SysEx data byte '{}' (expected hex 00-7F)", b)))
+                .collect::<Result<Vec<U7>, String>>()?;
+            Ok(ScreenTrigger::SysEx(prefix))
+        },
+        _ => Err(format!("Invalid trigger '{}' (expected 'cc CHANNEL NUMBER', 'program CHANNEL' or 'sysex BYTES...')", value))
+    }
+}
+
+fn parse_cc(value: &str) -> Result<ControlFunction, String> {
+    match value.parse::<u8>().ok().and_then(|cc| U7::try_from(cc).ok()) {
+        Some(cc) => Ok(ControlFunction(cc)),
+        None => Err(format!("Invalid CC '{}' (expected 0-127)", value))
+    }
+}
+
+fn parse_any_channel(value: &str) -> Result<Option<Channel>, String> {
+    if value == "any" {
+        Ok(None)
+    } else {
+        parse_channel(value).map(Some)
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "true" | "yes" => Ok(true),
+        "off" | "false" | "no" => Ok(false),
+        _ => Err(format!("Invalid setting '{}' (expected 'on' or 'off')", value))
+    }
+}
+
+fn parse_remap(value: &str) -> Result<Vec<(Channel, Channel)>, String> {
+    value.split_whitespace().map(|pair| {
+        let (from, to) = pair.split_once(':').ok_or_else(|| format!("Invalid remap '{}' (expected 'FROM:TO', eg. '1:2')", pair))?;
+        Ok((parse_channel(from)?, parse_channel(to)?))
+    }).collect()
+}
+
+fn parse_brightness(value: &str) -> Result<u8, String> {
+    match value.parse() {
+        Ok(b) if (1..=100).contains(&b) => Ok(b),
+        _ => Err(format!("Invalid brightness '{}' (expected 1-100)", value))
+    }
+}
+
+fn parse_night(value: &str) -> Result<Option<NightSchedule>, String> {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    match words.as_slice() {
+        ["off"] => Ok(None),
+        [times, brightness] => {
+            let (start, end) = times.split_once('-').ok_or_else(|| format!("Invalid night times '{}' (expected 'HH:MM-HH:MM')", times))?;
+            let brightness = match brightness.parse() {
+                Ok(b) if b <= 100 => b,
+                _ => return Err(format!("Invalid night brightness '{}' (expected 0-100)", brightness))
+            };
+            Ok(Some(NightSchedule {
+                start: parse_time(start)?,
+                end: parse_time(end)?,
+                brightness
+            }))
+        },
+        _ => Err(format!("Invalid night setting '{}' (expected 'HH:MM-HH:MM BRIGHTNESS' or 'off')", value))
+    }
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value, "%H:%M").map_err(|_| format!("Invalid time '{}' (expected HH:MM)", value))
+}
+
+fn parse_brightness_cc(value: &str) -> Result<Option<(Option<Channel>, ControlFunction)>, String> {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    match words.as_slice() {
+        ["off"] => Ok(None),
+        [channel, cc] => Ok(Some((parse_any_channel(channel)?, parse_cc(cc)?))),
+        _ => Err(format!("Invalid brightness CC '{}' (expected 'CHANNEL NUMBER' or 'off')", value))
+    }
+}
+
+fn parse_idle(value: &str) -> Result<Option<(Duration, IdleMode)>, String> {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    match words.as_slice() {
+        ["off"] => Ok(None),
+        [minutes, mode] => {
+            let minutes: u64 = minutes.parse().ok().filter(|m| *m > 0).ok_or_else(|| format!("Invalid idle minutes '{}' (must be 1 or more)", minutes))?;
+            let mode = IdleMode::ALL.into_iter().find(|m| m.name() == *mode)
+                .ok_or_else(|| format!("Invalid idle mode '{}' (expected {})", mode, IdleMode::ALL.map(|m| m.name()).join(", ")))?;
+            Ok(Some((Duration::from_secs(minutes * 60), mode)))
+        },
+        _ => Err(format!("Invalid idle setting '{}' (expected 'MINUTES MODE' or 'off')", value))
+    }
+}
+
+fn parse_max_hold(value: &str) -> Result<Option<Duration>, String> {
+    if value == "off" {
+        return Ok(None);
+    }
+    match value.parse::<u64>() {
+        Ok(seconds) if seconds > 0 => Ok(Some(Duration::from_secs(seconds))),
+        _ => Err(format!("Invalid max hold '{}' (expected seconds or 'off')", value))
+    }
+}
+
+fn parse_eviction(value: &str) -> Result<Eviction, String> {
+    Eviction::ALL.into_iter().find(|e| e.name() == value)
+        .ok_or_else(|| format!("Invalid eviction '{}' (expected {})", value, Eviction::ALL.map(|e| e.name()).join(", ")))
+}
+
+// check a strftime format is valid now, since formatting an invalid one panics
+fn parse_format(value: &str) -> Result<String, String> {
+    if StrftimeItems::new(value).any(|i| i == Item::Error) {
+        Err(format!("Invalid format '{}' (expected strftime, eg. '%H:%M')", value))
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+fn parse_color(value: &str) -> Result<LedColor, String> {
+    let hex = value.strip_prefix('#').filter(|h| h.len() == 6);
+    match hex.and_then(|h| u32::from_str_radix(h, 16).ok()) {
+        Some(rgb) => Ok(LedColor { red: (rgb >> 16) as u8, green: (rgb >> 8) as u8, blue: rgb as u8 }),
+        None => Err(format!("Invalid color '{}' (expected '#RRGGBB')", value))
+    }
+}
+
+fn parse_channel(value: &str) -> Result<Channel, String> {
+    let number: u8 = value.parse().map_err(|_| format!("Invalid MIDI channel '{}'", value))?;
+    if number < 1 {
+        return Err(format!("Invalid MIDI channel '{}'", value));
+    }
+    Channel::from_index(number - 1).map_err(|_| "MIDI channel must be between 1 and 16".to_string())
+}
+
+fn parse_mpe(value: &str) -> Result<MpeConfig, String> {
+    match value {
+        "off" => Ok(MpeConfig::Off),
+        "auto" => Ok(MpeConfig::Auto),
+        _ => match value.parse() {
+            Ok(members) if (1..=15).contains(&members) => Ok(MpeConfig::Members(members)),
+            _ => Err(format!("Invalid MPE setting '{}' (expected 'off', 'auto' or 1-15 member channels)", value))
+        }
+    }
+}
+
+fn parse_drums(value: &str) -> Result<Option<Vec<DrumPad>>, String> {
+    match value {
+        "off" => Ok(None),
+        "gm" => Ok(Some(DrumPad::GM_KIT.to_vec())),
+        _ => {
+            let mut pads = Vec::new();
+            for pad in value.split_whitespace() {
+                let (note, slots) = pad.split_once(':').ok_or_else(|| format!("Invalid drum pad '{}' (expected 'NOTE:FIRST-LAST')", pad))?;
+                let (first, last) = slots.split_once('-').unwrap_or((slots, slots));
+                let first = parse_slot(first)?;
+                let last = parse_slot(last)?;
+                if first > last {
+                    return Err(format!("Invalid drum pad '{}' (first slot is after last slot)", pad));
+                }
+                pads.push(DrumPad::new(parse_note(note)?, first, last));
+            }
+            Ok(Some(pads))
+        }
+    }
+}
+
+fn parse_slot(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(slot) if slot >= 1 => Ok(slot - 1),
+        _ => Err(format!("Invalid slot '{}' (must be 1 or more)", value))
+    }
+}
+
+fn parse_mapping(value: &str) -> Result<NoteMapping, String> {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    match words.as_slice() {
+        ["piano"] => Ok(NoteMapping::PIANO),
+        ["auto"] => Ok(NoteMapping::Auto),
+        ["linear", lowest, highest] => {
+            let lowest = parse_note(lowest)?;
+            let highest = parse_note(highest)?;
+            if lowest > highest {
+                return Err(format!("Lowest note {} is higher than highest note {}", lowest, highest));
+            }
+            Ok(NoteMapping::Linear(lowest, highest))
+        },
+        ["fixed", lowest] => Ok(NoteMapping::Fixed(parse_note(lowest)?)),
+        _ => Err(format!("Invalid note mapping '{}' (expected 'piano', 'auto', 'linear LOWEST HIGHEST' or 'fixed LOWEST')", value))
+    }
+}
+
+// parse a note as either a MIDI note number (eg. 60) or a note name (eg. C4, F#2, Bb-1)
+fn parse_note(value: &str) -> Result<Note, String> {
+    if let Ok(number) = value.parse::<u8>() {
+        return Note::try_from(number).map_err(|_| format!("Invalid note number {}", number));
+    }
+    let mut chars = value.chars();
+    let pitch_class: i16 = match chars.next().map(|c| c.to_ascii_uppercase()) {
+        Some('C') => 0,
+        Some('D') => 2,
+        Some('E') => 4,
+        Some('F') => 5,
+        Some('G') => 7,
+        Some('A') => 9,
+        Some('B') => 11,
+        _ => return Err(format!("Invalid note '{}'", value))
+    };
+    let mut rest = chars.as_str();
+    let accidental = if let Some(r) = rest.strip_prefix('#') {
+        rest = r;
+        1
+    } else if let Some(r) = rest.strip_prefix('b') {
+        rest = r;
+        -1
+    } else {
+        0
+    };
+    let octave: i16 = rest.parse().map_err(|_| format!("Invalid octave in note '{}'", value))?;
+    // checked, as a very large octave would overflow
+    octave.checked_add(1).and_then(|o| o.checked_mul(12)).and_then(|n| n.checked_add(pitch_class + accidental))
+        .and_then(|n| u8::try_from(n).ok())
+        .and_then(|n| Note::try_from(n).ok())
+        .ok_or_else(|| format!("Note '{}' is out of range", value))
+}
@@ -0,0 +1,193 @@
+use std::io::{self, Write};
+use rpi_led_matrix::{LedCanvas, LedColor, LedMatrix, LedMatrixOptions};
+
+use crate::error::{Error, Result};
+use crate::helper::scale;
+
+pub trait Canvas {
+    fn size(&self) -> (i32, i32);
+    fn clear(&mut self);
+    fn set(&mut self, x: i32, y: i32, color: &LedColor);
+
+    fn draw_line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: &LedColor) {
+        // Bresenham's line algorithm
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+        loop {
+            self.set(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+}
+
+pub trait Display {
+    fn canvas(&mut self) -> &mut dyn Canvas;
+    fn swap(&mut self);
+    fn set_brightness(&mut self, brightness: u8);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Backend {
+    Led, Terminal
+}
+
+impl Backend {
+    pub fn open(&self, cols: u32, rows: u32, brightness: u8) -> Result<Box<dyn Display>> {
+        Ok(match self {
+            Backend::Led => Box::new(LedDisplay::new(cols, rows, brightness)?),
+            Backend::Terminal => Box::new(TerminalDisplay::new(cols, rows, brightness))
+        })
+    }
+}
+
+// scale a colour by a brightness percentage
+fn dim(color: &LedColor, brightness: u8) -> LedColor {
+    if brightness >= 100 {
+        *color
+    } else {
+        scale(color, (brightness as usize * 256 / 100) as u8)
+    }
+}
+
+// the led matrix library only sets brightness when opened, so any changes after that are applied to each pixel drawn
+pub struct LedDisplay {
+    matrix: LedMatrix,
+    canvas: Option<LedCanvas>,
+    brightness: u8
+}
+
+impl LedDisplay {
+    pub fn new(cols: u32, rows: u32, brightness: u8) -> Result<Self> {
+        let mut options = LedMatrixOptions::new();
+        options.set_rows(rows);
+        options.set_cols(cols);
+        let matrix = LedMatrix::new(Some(options), None).map_err(|e| Error::Display(format!("Cannot open led panel: {}", e)))?;
+        let canvas = matrix.offscreen_canvas();
+        Ok(Self {
+            matrix,
+            canvas: Some(canvas),
+            brightness
+        })
+    }
+
+    fn led_canvas(&mut self) -> &mut LedCanvas {
+        self.canvas.as_mut().unwrap()
+    }
+}
+
+impl Canvas for LedDisplay {
+    fn size(&self) -> (i32, i32) {
+        self.canvas.as_ref().unwrap().canvas_size()
+    }
+
+    fn clear(&mut self) {
+        self.led_canvas().clear()
+    }
+
+    fn set(&mut self, x: i32, y: i32, color: &LedColor) {
+        let color = dim(color, self.brightness);
+        self.led_canvas().set(x, y, &color)
+    }
+
+    fn draw_line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: &LedColor) {
+        let color = dim(color, self.brightness);
+        self.led_canvas().draw_line(x0, y0, x1, y1, &color)
+    }
+}
+
+impl Display for LedDisplay {
+    fn canvas(&mut self) -> &mut dyn Canvas {
+        self
+    }
+
+    fn swap(&mut self) {
+        let canvas = self.canvas.take().unwrap();
+        self.canvas = Some(self.matrix.swap(canvas));
+    }
+
+    fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
+}
+
+// renders the panel in a terminal using 24-bit colour, for developing without an led panel
+pub struct TerminalDisplay {
+    width: i32,
+    height: i32,
+    brightness: u8,
+    pixels: Vec<LedColor>
+}
+
+impl TerminalDisplay {
+    const BLANK: LedColor = LedColor { red: 0, green: 0, blue: 0 };
+
+    pub fn new(cols: u32, rows: u32, brightness: u8) -> Self {
+        print!("\x1b[2J");
+        Self {
+            width: cols as i32,
+            height: rows as i32,
+            brightness,
+            pixels: vec![Self::BLANK; (cols * rows) as usize]
+        }
+    }
+
+    fn pixel(&self, x: i32, y: i32) -> (u8, u8, u8) {
+        let p = if y < self.height { &self.pixels[(y * self.width + x) as usize] } else { &Self::BLANK };
+        let p = dim(p, self.brightness);
+        (p.red, p.green, p.blue)
+    }
+}
+
+impl Canvas for TerminalDisplay {
+    fn size(&self) -> (i32, i32) {
+        (self.width, self.height)
+    }
+
+    fn clear(&mut self) {
+        self.pixels.fill(Self::BLANK);
+    }
+
+    fn set(&mut self, x: i32, y: i32, color: &LedColor) {
+        if x >= 0 && x < self.width && y >= 0 && y < self.height {
+            self.pixels[(y * self.width + x) as usize] = *color;
+        }
+    }
+}
+
+impl Display for TerminalDisplay {
+    fn canvas(&mut self) -> &mut dyn Canvas {
+        self
+    }
+
+    fn swap(&mut self) {
+        // draw 2 rows of pixels per line of text, using the upper half block character
+        let mut frame = String::from("\x1b[H");
+        for y in (0..self.height).step_by(2) {
+            for x in 0..self.width {
+                let (r1, g1, b1) = self.pixel(x, y);
+                let (r2, g2, b2) = self.pixel(x, y + 1);
+                frame.push_str(&format!("\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m\u{2580}", r1, g1, b1, r2, g2, b2));
+            }
+            frame.push_str("\x1b[0m\n");
+        }
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(frame.as_bytes()).ok();
+        stdout.flush().ok();
+    }
+
+    fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
+}
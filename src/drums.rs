@@ -0,0 +1,103 @@
+use std::time::{Duration, Instant};
+use rpi_led_matrix::LedColor;
+use wmidi::{Note, Velocity, U7};
+
+use crate::helper::scale;
+use crate::display::Canvas;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DrumPad {
+    pub note: Note,
+    pub first_slot: usize,
+    pub last_slot: usize
+}
+
+impl DrumPad {
+    pub const fn new(note: Note, first_slot: usize, last_slot: usize) -> Self {
+        Self {
+            note,
+            first_slot,
+            last_slot
+        }
+    }
+
+    // General MIDI percussion spread across 24 slots (the width of a 32x16 panel)
+    pub const GM_KIT: [DrumPad; 8] = [
+        DrumPad::new(Note::C2, 0, 3), // kick
+        DrumPad::new(Note::D2, 4, 7), // snare
+        DrumPad::new(Note::A2, 8, 9), // low tom
+        DrumPad::new(Note::C3, 10, 11), // mid tom
+        DrumPad::new(Note::D3, 12, 13), // high tom
+        DrumPad::new(Note::FSharp2, 14, 16), // closed hi-hat
+        DrumPad::new(Note::ASharp2, 17, 19), // open hi-hat
+        DrumPad::new(Note::CSharp3, 20, 23) // crash
+    ];
+}
+
+pub struct DrumPads {
+    pads: Vec<DrumPad>,
+    hits: Vec<Option<(Velocity, Instant)>>
+}
+
+impl DrumPads {
+    const DECAY_MS: u128 = 150;
+
+    pub fn new(pads: Vec<DrumPad>) -> Self {
+        let hits = vec![None; pads.len()];
+        Self {
+            pads,
+            hits
+        }
+    }
+
+    pub fn pads(&self) -> &[DrumPad] {
+        &self.pads
+    }
+
+    pub fn hit(&mut self, n: Note, v: Velocity, when: Instant) {
+        // drums often send no NoteOff (or send it immediately), so only the hit matters
+        if v > U7::MIN {
+            for i in 0..self.pads.len() {
+                if self.pads[i].note == n {
+                    self.hits[i] = Some((v, when));
+                }
+            }
+        }
+    }
+
+    pub fn animate(&mut self) -> bool {
+        let mut changed = false;
+        for hit in self.hits.iter_mut() {
+            if let Some((_, when)) = hit {
+                if when.elapsed().as_millis() >= Self::DECAY_MS {
+                    *hit = None;
+                }
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    // while any pad is fading, it needs redrawing as often as possible
+    pub fn next_animation(&self) -> Option<Duration> {
+        self.hits.iter().flatten().next().map(|_| Duration::ZERO)
+    }
+
+    pub fn draw(&self, canvas: &mut dyn Canvas, first_column: i32, slots: usize, color: &LedColor) {
+        let height = canvas.size().1;
+        for i in 0..self.pads.len() {
+            if let Some((v, when)) = self.hits[i] {
+                let elapsed = when.elapsed().as_millis();
+                if elapsed < Self::DECAY_MS {
+                    let v: u8 = v.into();
+                    let brightness = (v as u128 * 2 + 1) * (Self::DECAY_MS - elapsed) / Self::DECAY_MS;
+                    let faded = scale(color, brightness as u8);
+                    for s in self.pads[i].first_slot..=self.pads[i].last_slot.min(slots - 1) {
+                        let x = first_column + s as i32;
+                        canvas.draw_line(x, 0, x, height - 1, &faded);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::any::Any;
+use std::fmt;
+use std::time::{Duration, Instant};
+use rpi_led_matrix::LedColor;
+
+use crate::display::Canvas;
+use crate::font::Fonts;
+
+#[derive(Debug)]
+pub enum Error {
+    Display(String),
+    Midi(String),
+    Config(String),
+    Font(String),
+    Log(String),
+    Panic(String)
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    const COLOR: LedColor = LedColor { red: 255, green: 0, blue: 0 };
+
+    // short code which fits on the panel, so the problem can be identified without a terminal
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Display(_) => "E1",
+            Error::Midi(_) => "E2",
+            Error::Config(_) => "E3",
+            Error::Font(_) => "E4",
+            Error::Log(_) => "E5",
+            Error::Panic(_) => "E9"
+        }
+    }
+
+    pub fn from_panic(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(s) => *s,
+            Err(payload) => payload.downcast_ref::<&str>().map(|s| s.to_string()).unwrap_or_else(|| "Unknown".to_string())
+        };
+        Error::Panic(message)
+    }
+
+    pub fn draw(&self, canvas: &mut dyn Canvas, fonts: &Fonts) {
+        let (width, height) = canvas.size();
+        let code = self.code();
+        let font = fonts.fitting(code, width, height);
+        font.draw(canvas, code, (width - font.width(code)) / 2, font.middle(height), &Self::COLOR);
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Display(e) => write!(f, "Display error: {}", e),
+            Error::Midi(e) => write!(f, "MIDI error: {}", e),
+            Error::Config(e) => write!(f, "Config error: {}", e),
+            Error::Font(e) => write!(f, "Font error: {}", e),
+            Error::Log(e) => write!(f, "Log error: {}", e),
+            Error::Panic(e) => write!(f, "Panic: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for Error { }
+
+// exponentially increasing delay between retries of something which keeps failing
+pub struct Backoff {
+    failures: u32,
+    retry_at: Instant
+}
+
+impl Backoff {
+    const INITIAL_MS: u64 = 1000;
+    const MAX_MS: u64 = 30000;
+
+    pub fn new() -> Self {
+        Self {
+            failures: 0,
+            retry_at: Instant::now()
+        }
+    }
+
+    pub fn ready(&self) -> bool {
+        Instant::now() >= self.retry_at
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    // record a failure, returning how long until the next retry
+    pub fn failed(&mut self) -> Duration {
+        let delay = Duration::from_millis((Self::INITIAL_MS << self.failures.min(16)).min(Self::MAX_MS));
+        self.failures += 1;
+        self.retry_at = Instant::now() + delay;
+        delay
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.retry_at = Instant::now();
+    }
+}
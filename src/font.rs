@@ -0,0 +1,238 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use rpi_led_matrix::LedColor;
+
+use crate::config::Align;
+use crate::display::Canvas;
+use crate::error::{Error, Result};
+
+struct Glyph {
+    advance: i32,
+    width: i32,
+    height: i32,
+    x_offset: i32,
+    y_offset: i32,
+    bits: u32,
+    rows: Vec<u64>
+}
+
+pub struct Font {
+    ascent: i32,
+    descent: i32,
+    glyphs: HashMap<char, Glyph>,
+    default_char: Option<char>
+}
+
+impl Font {
+    const DEFAULT_BDF: &'static str = include_str!("../6x9.bdf");
+
+    pub fn embedded() -> Self {
+        Self::parse(Self::DEFAULT_BDF).expect("Embedded font is invalid")
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(|e| Error::Font(format!("Cannot read '{}': {}", path, e)))?;
+        Self::parse(&text).map_err(|e| Error::Font(format!("Invalid '{}': {}", path, e)))
+    }
+
+    fn parse(bdf: &str) -> Result<Self, String> {
+        let mut ascent = None;
+        let mut descent = None;
+        let mut default_char = None;
+        let mut glyphs = HashMap::new();
+        let mut lines = bdf.lines();
+        while let Some(line) = lines.next() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("FONT_ASCENT") => ascent = words.next().and_then(|w| w.parse().ok()),
+                Some("FONT_DESCENT") => descent = words.next().and_then(|w| w.parse().ok()),
+                Some("DEFAULT_CHAR") => default_char = words.next().and_then(|w| w.parse().ok()).and_then(char::from_u32),
+                Some("STARTCHAR") => {
+                    if let Some((c, glyph)) = Self::parse_glyph(&mut lines)? {
+                        glyphs.insert(c, glyph);
+                    }
+                },
+                _ => { }
+            }
+        }
+        Ok(Self {
+            ascent: ascent.ok_or("Missing FONT_ASCENT")?,
+            descent: descent.ok_or("Missing FONT_DESCENT")?,
+            glyphs,
+            default_char
+        })
+    }
+
+    fn parse_glyph<'a>(lines: &mut impl Iterator<Item = &'a str>) -> Result<Option<(char, Glyph)>, String> {
+        let mut c = None;
+        let mut advance = 0;
+        let mut bbx = [0; 4];
+        let mut bits = 0;
+        let mut rows = Vec::new();
+        let mut in_bitmap = false;
+        for line in lines.by_ref() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("ENDCHAR") => {
+                    let glyph = Glyph {
+                        advance,
+                        width: bbx[0],
+                        height: bbx[1],
+                        x_offset: bbx[2],
+                        y_offset: bbx[3],
+                        bits,
+                        rows
+                    };
+                    return Ok(c.map(|c| (c, glyph)));
+                },
+                Some(hex) if in_bitmap => {
+                    if hex.len() > 16 {
+                        return Err("Glyphs wider than 64 pixels are not supported".to_string());
+                    }
+                    rows.push(u64::from_str_radix(hex, 16).map_err(|_| format!("Invalid BITMAP row '{}'", hex))?);
+                    bits = hex.len() as u32 * 4;
+                },
+                Some("ENCODING") => c = words.next().and_then(|w| w.parse().ok()).and_then(char::from_u32),
+                Some("DWIDTH") => advance = words.next().and_then(|w| w.parse().ok()).ok_or("Invalid DWIDTH")?,
+                Some("BBX") => {
+                    for b in bbx.iter_mut() {
+                        *b = words.next().and_then(|w| w.parse().ok()).ok_or("Invalid BBX")?;
+                    }
+                },
+                Some("BITMAP") => in_bitmap = true,
+                _ => { }
+            }
+        }
+        Err("Missing ENDCHAR".to_string())
+    }
+
+    pub fn height(&self) -> i32 {
+        self.ascent + self.descent
+    }
+
+    pub fn ascent(&self) -> i32 {
+        self.ascent
+    }
+
+    fn glyph(&self, c: char) -> Option<&Glyph> {
+        self.glyphs.get(&c).or_else(|| self.default_char.and_then(|d| self.glyphs.get(&d)))
+    }
+
+    pub fn width(&self, text: &str) -> i32 {
+        text.chars().filter_map(|c| self.glyph(c)).map(|g| g.advance).sum()
+    }
+
+    // y of the first row below the baseline (matching the led matrix library) to centre the text vertically
+    pub fn middle(&self, height: i32) -> i32 {
+        (height - self.height() + 1) / 2 + self.ascent
+    }
+
+    // draw text with the baseline just above y (matching the led matrix library), returning the width drawn
+    pub fn draw(&self, canvas: &mut dyn Canvas, text: &str, x: i32, y: i32, color: &LedColor) -> i32 {
+        let mut left = x;
+        for glyph in text.chars().filter_map(|c| self.glyph(c)) {
+            let top = y - glyph.height - glyph.y_offset;
+            for (r, row) in glyph.rows.iter().enumerate() {
+                for col in 0..glyph.width.min(glyph.bits as i32) {
+                    if row & (1 << (glyph.bits as i32 - 1 - col)) != 0 {
+                        canvas.set(left + glyph.x_offset + col, top + r as i32, color);
+                    }
+                }
+            }
+            left += glyph.advance;
+        }
+        left - x
+    }
+}
+
+pub struct Fonts {
+    fonts: Vec<Font>
+}
+
+impl Fonts {
+    pub fn new(fonts: Vec<Font>) -> Self {
+        let mut fonts = fonts;
+        fonts.insert(0, Font::embedded());
+        fonts.sort_by_key(|f| f.height());
+        Self {
+            fonts
+        }
+    }
+
+    // the largest font which fits the text within the given size, otherwise the smallest font
+    pub fn fitting(&self, text: &str, width: i32, height: i32) -> &Font {
+        self.fonts.iter().rev()
+            .find(|f| f.height() <= height && f.width(text) <= width)
+            .unwrap_or(&self.fonts[0])
+    }
+}
+
+// scrolls text which is too wide for the panel, so it is only redrawn when it moves
+pub struct Scroll {
+    started: Instant,
+    step: u128,
+    scrolling: Cell<bool>
+}
+
+impl Scroll {
+    const STEP_MS: u128 = 80;
+    const GAP: i32 = 8;
+
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            step: 0,
+            scrolling: Cell::new(false)
+        }
+    }
+
+    // true if the text has scrolled since it was last checked
+    pub fn animate(&mut self) -> bool {
+        if !self.scrolling.get() {
+            return false;
+        }
+        let step = self.started.elapsed().as_millis() / Self::STEP_MS;
+        let changed = step != self.step;
+        self.step = step;
+        changed
+    }
+
+    // time until the text next moves, if scrolling
+    pub fn next_animation(&self) -> Option<Duration> {
+        if self.scrolling.get() {
+            let ms = Self::STEP_MS - self.started.elapsed().as_millis() % Self::STEP_MS;
+            Some(Duration::from_millis(ms as u64))
+        } else {
+            None
+        }
+    }
+
+    // set after drawing, as only drawing knows whether the text fits
+    pub fn set_scrolling(&self, scrolling: bool) {
+        self.scrolling.set(scrolling);
+    }
+
+    // draw a line of text centred vertically within the given rows, scrolling it if too wide, returning true if scrolling
+    pub fn draw_line(&self, canvas: &mut dyn Canvas, font: &Font, text: &str, rows: Range<i32>, align: Align, color: &LedColor) -> bool {
+        let width = canvas.size().0;
+        let text_width = font.width(text);
+        let y = rows.start + font.middle(rows.len() as i32);
+        if text_width > width {
+            let offset = (self.started.elapsed().as_millis() / Self::STEP_MS) as i32 % (text_width + Self::GAP);
+            font.draw(canvas, text, -offset, y, color);
+            font.draw(canvas, text, text_width + Self::GAP - offset, y, color);
+            true
+        } else {
+            let x = match align {
+                Align::Left => 0,
+                Align::Center => (width - text_width) / 2,
+                Align::Right => width - text_width
+            };
+            font.draw(canvas, text, x, y, color);
+            false
+        }
+    }
+}
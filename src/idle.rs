@@ -0,0 +1,95 @@
+use std::time::{Duration, Instant};
+use chrono::Local;
+
+use crate::config::Config;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IdleMode {
+    /// Turn the panel off
+    Blank,
+    /// Show the clock, moving it around the panel to avoid burn-in
+    Clock,
+    /// Keep showing the same thing, but dimmed
+    Dim
+}
+
+impl IdleMode {
+    pub const ALL: [IdleMode; 3] = [IdleMode::Blank, IdleMode::Clock, IdleMode::Dim];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            IdleMode::Blank => "blank",
+            IdleMode::Clock => "clock",
+            IdleMode::Dim => "dim"
+        }
+    }
+}
+
+pub struct Idle {
+    timeout: Option<(Duration, IdleMode)>,
+    last_active: Instant,
+    shown: bool
+}
+
+impl Idle {
+    const DIM_PERCENT: usize = 20;
+
+    pub fn new(config: &Config) -> Self {
+        Self {
+            timeout: config.idle,
+            last_active: Instant::now(),
+            shown: false
+        }
+    }
+
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.timeout = config.idle;
+    }
+
+    // restart the timeout, returning true if this woke from idle
+    pub fn wake(&mut self) -> bool {
+        self.last_active = Instant::now();
+        let woke = self.shown;
+        self.shown = false;
+        woke
+    }
+
+    // true if this has just become idle
+    pub fn animate(&mut self) -> bool {
+        let idle = self.mode().is_some();
+        let changed = idle != self.shown;
+        self.shown = idle;
+        changed
+    }
+
+    // the idle mode, if idle
+    pub fn mode(&self) -> Option<IdleMode> {
+        match self.timeout {
+            Some((timeout, mode)) if self.last_active.elapsed() >= timeout => Some(mode),
+            _ => None
+        }
+    }
+
+    // time until becoming idle, if not already
+    pub fn next_animation(&self) -> Option<Duration> {
+        match self.timeout {
+            Some((timeout, _)) if !self.shown => Some(timeout.saturating_sub(self.last_active.elapsed())),
+            _ => None
+        }
+    }
+
+    pub fn dim(&self, brightness: u8) -> u8 {
+        if self.mode() == Some(IdleMode::Dim) {
+            (brightness as usize * Self::DIM_PERCENT / 100) as u8
+        } else {
+            brightness
+        }
+    }
+}
+
+// move something around the spare space once a minute (across then down), so the same pixels aren't always lit
+pub fn shift(spare_width: i32, spare_height: i32) -> (i32, i32) {
+    let (columns, rows) = (spare_width.max(0) as i64 + 1, spare_height.max(0) as i64 + 1);
+    let step = Local::now().timestamp() / 60;
+    ((step % columns) as i32, (step / columns % rows) as i32)
+}
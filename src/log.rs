@@ -0,0 +1,133 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
+use chrono::Local;
+
+use crate::error::{Error, Result};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error, Warn, Info, Debug
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG"
+        }
+    }
+
+    // syslog priority, understood by the systemd journal when prefixed as <N>
+    fn priority(&self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug => 7
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    Stderr,
+    /// stderr without timestamps, with priority prefixes for the systemd journal
+    Journal,
+    File(String)
+}
+
+impl Target {
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "stderr" => Target::Stderr,
+            "journal" => Target::Journal,
+            path => Target::File(path.to_string())
+        }
+    }
+}
+
+struct Logger {
+    level: Level,
+    journal: bool,
+    file: Option<Mutex<File>>
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+pub fn init(level: Level, target: &Target) -> Result<()> {
+    let file = match target {
+        Target::File(path) => {
+            let file = fs::File::options().create(true).append(true).open(path).map_err(|e| Error::Log(format!("Cannot open '{}': {}", path, e)))?;
+            Some(Mutex::new(file))
+        },
+        _ => None
+    };
+    let logger = Logger {
+        level,
+        journal: *target == Target::Journal,
+        file
+    };
+    LOGGER.set(logger).map_err(|_| Error::Log("Already initialised".to_string()))?;
+    Ok(())
+}
+
+pub fn enabled(level: Level) -> bool {
+    // before init, log at the default level to stderr
+    level <= LOGGER.get().map(|l| l.level).unwrap_or(Level::Info)
+}
+
+pub fn log(level: Level, message: &str) {
+    let line = match LOGGER.get() {
+        Some(logger) if logger.journal => format!("<{}>{}\n", level.priority(), message),
+        _ => format!("{} {:<5} {}\n", Local::now().format("%Y-%m-%d %H:%M:%S%.3f"), level.name(), message)
+    };
+    match LOGGER.get().and_then(|l| l.file.as_ref()) {
+        Some(file) => {
+            if let Ok(mut file) = file.lock() {
+                file.write_all(line.as_bytes()).ok();
+            }
+        },
+        None => {
+            io::stderr().lock().write_all(line.as_bytes()).ok();
+        }
+    }
+}
+
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            $crate::log::log($level, &format!($($arg)*));
+        }
+    }
+}
+
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::log::log_at!($crate::log::Level::Error, $($arg)*) }
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::log::log_at!($crate::log::Level::Warn, $($arg)*) }
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => { $crate::log::log_at!($crate::log::Level::Info, $($arg)*) }
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::log::log_at!($crate::log::Level::Debug, $($arg)*) }
+}
+
+pub(crate) use {log_at, log_error, log_warn, log_info, log_debug};
@@ -2,101 +2,290 @@ mod notes;
 mod meter;
 mod midi;
 mod helper;
+mod config;
+mod drums;
+mod chords;
+mod pianoroll;
+mod screen;
+mod clock;
+mod tempo;
+mod display;
+mod args;
+mod font;
+mod log;
+mod error;
+mod systemd;
+mod signals;
+mod brightness;
+mod idle;
+mod sysex;
+mod banner;
+mod thru;
+mod mpe;
 
+use args::Args;
+use brightness::Brightness;
+use config::{Config, ConfigWatcher};
+use clock::Clock;
+use display::Display;
+use error::{Backoff, Error, Result};
+use idle::{Idle, IdleMode};
+use font::{Font, Fonts};
+use log::{log_debug, log_error, log_info, log_warn};
 use meter::PanelMeter;
-use midi::NonBlockingInputDevice;
-use rpi_led_matrix::LedCanvas;
-use rpi_led_matrix::{LedMatrix, LedColor, LedFont, LedMatrixOptions};
-use chrono::Local;
+use midi::{MidiConnection, MidiDevice, MidiOutput, NonBlockingInputDevice};
+use thru::Thru;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
 use std::time::Duration;
 use std::thread;
-use std::fs;
-use std::error::Error;
+use std::process;
+use std::env;
 use std::time::Instant;
 
-const CLOCK_UPDATE_MS: u128 = 1000;
-const METER_UPDATE_MS: u128 = 10; //100Hz
+const CLOCK_UPDATE: Duration = Duration::from_millis(80); // fast enough to scroll text
+const CONNECT_INTERVAL: Duration = Duration::from_millis(1000);
+const METER_UPDATE: Duration = Duration::from_millis(10); //100Hz
+const METER_MAX_WAIT: Duration = Duration::from_millis(1000); // to keep checking config changes and the watchdog
+const FAILURES_BEFORE_ERROR_SHOWN: u32 = 3; // the first few are often just a device being plugged in
 
 fn main() {
+    let args = match Args::parse(env::args()) {
+        Ok(args) => args,
+        Err(err) => {
+            println!("{}\n\n{}", err, Args::USAGE);
+            process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", Args::USAGE);
+        return;
+    }
+    if args.list_devices {
+        match MidiDevice::list() {
+            Ok(devices) => for device in devices {
+                println!("{}\t{}", device.path, device.name);
+            },
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Err(err) = log::init(args.log_level, &args.log_target) {
+        eprintln!("{}", err);
+        process::exit(2);
+    }
+    log_info!("Starting midi-panel-meter {}", env!("CARGO_PKG_VERSION"));
+    signals::handle();
+    let mut config = load_config(&args).unwrap_or_else(|err| {
+        log_error!("Error loading config, using defaults: {}", err);
+        Config::default()
+    });
+    let mut watcher = ConfigWatcher::new(args.config_path());
     // set up screen
-    let mut options = LedMatrixOptions::new();
-    options.set_rows(16);
-    options.set_cols(32);
-    let matrix = LedMatrix::new(Some(options), None).unwrap();
-    // draw clock while waiting for midi
-    let mut canvas = matrix.offscreen_canvas();
-    let font_path = Path::new("6x9.bdf");
-    let font = LedFont::new(&font_path).unwrap();
-    let color = LedColor { red: 255, green: 255, blue: 255 };
-    let mut colon = true;
-    loop {
+    let mut display = match args.backend.open(args.cols, args.rows, args.brightness) {
+        Ok(display) => display,
+        Err(err) => {
+            // there is nowhere to show this error, so exit and hope to be restarted
+            log_error!("{}", err);
+            process::exit(1);
+        }
+    };
+    let mut brightness = Brightness::new(&config, args.brightness);
+    // load any extra fonts
+    let mut extra_fonts = Vec::new();
+    for path in &args.fonts {
+        match Font::load(path) {
+            Ok(font) => {
+                log_info!("Loaded font {}", path);
+                extra_fonts.push(font)
+            },
+            Err(err) => log_error!("Error loading font: {}", err)
+        }
+    }
+    let fonts = Fonts::new(extra_fonts);
+    systemd::notify("READY=1");
+    // draw clock while waiting for midi, or the error code if it keeps failing
+    let mut clock = Clock::new(&config.clock);
+    let mut idle = Idle::new(&config);
+    let mut backoff = Backoff::new();
+    let mut last_error: Option<Error> = None;
+    let mut last_attempt: Option<Instant> = None;
+    let mut redraw = true;
+    log_info!("Waiting for MIDI device");
+    while !signals::terminating() {
         let updated = Instant::now();
-        let time = if colon {
-            colon = false;
-            format!("{}", Local::now().format("%H:%M"))
-        } else  {
-            colon = true;
-            format!("{}", Local::now().format("%H %M"))
-        };
-        canvas.clear();
-        canvas.draw_text(&font, &time, 1, 11, &color, 0, false);
-        canvas = matrix.swap(canvas);
-        if let Some(device) = list_files("/dev", "midi").unwrap().into_iter().next() {
-            match NonBlockingInputDevice::open(&device, true) {
-                Ok(midi) => canvas = show_midi_panel(midi, canvas, &matrix),
-                Err(err) => println!("Error opening MIDI device: {}", err) // Permission denied (os error 13) when running with sudo, unless root is added to 'audio' group
+        systemd::watchdog();
+        if reload_config_if_changed(&args, &mut config, &mut watcher) {
+            brightness.reconfigure(&config, args.brightness);
+            idle.reconfigure(&config);
+            clock.reconfigure(&config.clock);
+            redraw = true;
+        }
+        if clock.animate() | idle.animate() || redraw {
+            display.set_brightness(idle.dim(brightness.clock()));
+            let canvas = display.canvas();
+            canvas.clear();
+            match (&last_error, idle.mode()) {
+                (Some(err), _) if backoff.failures() >= FAILURES_BEFORE_ERROR_SHOWN => err.draw(canvas, &fonts),
+                (_, Some(IdleMode::Blank)) => { },
+                (_, Some(IdleMode::Clock)) => clock.draw_shifted(canvas, &fonts),
+                _ => clock.draw(canvas, &fonts)
+            }
+            display.swap();
+            redraw = false;
+        }
+        if backoff.ready() && last_attempt.is_none_or(|a| a.elapsed() >= CONNECT_INTERVAL) {
+            last_attempt = Some(Instant::now());
+            redraw = true;
+            match connect_midi(&args, display.as_mut(), &fonts, &mut config, &mut watcher, &mut brightness) {
+                Ok(connected) => {
+                    if connected {
+                        idle.wake();
+                    }
+                    backoff.succeeded();
+                    last_error = None;
+                },
+                Err(err) => {
+                    let delay = backoff.failed();
+                    log_warn!("{} (retrying in {}s)", err, delay.as_secs());
+                    last_error = Some(err);
+                }
             }
         }
-        let ms = updated.elapsed().as_millis();
-        if ms < CLOCK_UPDATE_MS {
-            thread::sleep(Duration::from_millis((CLOCK_UPDATE_MS - ms).try_into().unwrap()));
+        if let Some(remaining) = CLOCK_UPDATE.checked_sub(updated.elapsed()) {
+            thread::sleep(remaining);
         }
     }
+    // blank the panel rather than leaving the last frame showing
+    log_info!("Stopping");
+    systemd::notify("STOPPING=1");
+    display.canvas().clear();
+    display.swap();
 }
 
-fn list_files(root: &str, prefix: &str) -> Result<Vec<String>, Box<dyn Error>> {
-    let md = fs::metadata(root)?;
-    if md.is_dir() {
-        let mut files = Vec::new();
-        for entry in fs::read_dir(root)? {
-            let path = entry?.path();
-            if !path.is_dir() && path.file_name().unwrap().to_string_lossy().starts_with(prefix) {
-                files.push(path.display().to_string());
-            }
+// if a MIDI device is found, show the panel until it is disconnected, returning true if it was shown
+fn connect_midi(args: &Args, display: &mut dyn Display, fonts: &Fonts, config: &mut Config, watcher: &mut ConfigWatcher, brightness: &mut Brightness) -> Result<bool> {
+    if let Some(device) = MidiDevice::find(args.device.as_deref())? {
+        // Permission denied (os error 13) when running with sudo, unless root is added to 'audio' group
+        let midi = NonBlockingInputDevice::open(&device.path, args.include_clock_ticks)?;
+        log_info!("Reading MIDI from {} ({})", device.path, device.name);
+        // only needed to reply to SysEx, and many keyboards have no MIDI OUT
+        let output = MidiOutput::open(&device.path).inspect_err(|err| log_debug!("{}", err)).ok();
+        let midi = MidiConnection::start(midi, output);
+        // a bug in drawing shouldn't leave the panel dark, so recover and reconnect
+        panic::catch_unwind(AssertUnwindSafe(|| show_midi_panel(midi, display, fonts, args, config, watcher, brightness)))
+            .unwrap_or_else(|payload| Err(Error::from_panic(payload)))?;
+        if !signals::terminating() {
+            log_info!("MIDI device {} disconnected, waiting to reconnect", device.path);
         }
-        files.sort();
-        Ok(files)
+        Ok(true)
     } else {
-        Ok(vec![root.to_string()])
+        Ok(false)
     }
 }
 
-fn show_midi_panel(mut midi: NonBlockingInputDevice, mut canvas: LedCanvas, matrix: &LedMatrix) -> LedCanvas {
-    let mut panel = PanelMeter::new();
-    panel.draw(&mut canvas);
-    canvas = matrix.swap(canvas);
-    while midi.is_connected() {
-        let updated = Instant::now();
-        let mut changed = false;
-        while let Some(message) = match midi.read() {
-            Ok(opt) => opt,
-            Err(err) => {
-                println!("Error reading MIDI device: {}", err);
-                return canvas;
+// load config if present, otherwise defaults
+fn load_config(args: &Args) -> Result<Config> {
+    let path = args.config_path();
+    if args.config.is_some() || Path::new(path).exists() {
+        let config = Config::load(path)?;
+        log_info!("Loaded config from {}", path);
+        Ok(config)
+    } else {
+        Ok(Config::default())
+    }
+}
+
+// reload config on SIGHUP or when the file changes, returning true if it was reloaded
+fn reload_config_if_changed(args: &Args, config: &mut Config, watcher: &mut ConfigWatcher) -> bool {
+    // check both, so a SIGHUP isn't left pending
+    let requested = signals::take_reload();
+    if !watcher.changed() && !requested {
+        return false;
+    }
+    match load_config(args) {
+        Ok(loaded) => {
+            *config = loaded;
+            true
+        },
+        Err(err) => {
+            log_error!("Error reloading config, keeping current config: {}", err);
+            false
+        }
+    }
+}
+
+// the clock follows the night schedule even while a device is connected
+fn panel_brightness(panel: &PanelMeter, brightness: &Brightness) -> u8 {
+    let level = if panel.showing_clock() { brightness.clock() } else { brightness.meter() };
+    panel.idle().dim(level)
+}
+
+fn show_midi_panel(mut midi: MidiConnection, display: &mut dyn Display, fonts: &Fonts, args: &Args, config: &mut Config, watcher: &mut ConfigWatcher, brightness: &mut Brightness) -> Result<()> {
+    let (width, height) = display.canvas().size();
+    let mut panel = PanelMeter::new(config, width, height);
+    let mut thru = Thru::new(&config.thru);
+    display.set_brightness(panel_brightness(&panel, brightness));
+    panel.draw(display.canvas(), fonts);
+    display.swap();
+    let mut drawn = Instant::now();
+    let mut changed = false;
+    let mut timeout = Duration::ZERO;
+    while midi.is_connected() && !signals::terminating() {
+        systemd::watchdog();
+        if reload_config_if_changed(args, config, watcher) {
+            brightness.reconfigure(config, args.brightness);
+            panel.reconfigure(config);
+            thru.reconfigure(&config.thru);
+            changed = true;
+        }
+        thru.connect();
+        // handle everything which has arrived (waiting up to the timeout for the first), but not so much that it never draws
+        let mut wait = timeout;
+        let mut handle_until = None;
+        while let Some((received, message)) = midi.receive(wait)? {
+            log_debug!("{:?}", message);
+            thru.send(&message);
+            if brightness.handle(&message) {
+                panel.activity(&message, received);
+            } else {
+                panel.handle(message, received);
+            }
+            for reply in panel.take_replies() {
+                if let Err(err) = midi.send(&reply) {
+                    log_warn!("Cannot reply to SysEx: {}", err);
+                }
             }
-        } {
-            panel.handle(message);
             changed = true;
+            if Instant::now() >= *handle_until.get_or_insert_with(|| Instant::now() + METER_UPDATE) {
+                break;
+            }
+            wait = Duration::ZERO;
+        }
+        let dropped = midi.dropped();
+        if dropped > 0 {
+            log_warn!("Dropped {} MIDI messages because they arrived faster than they could be handled", dropped);
         }
-        if changed {
-            panel.draw(&mut canvas);
-            canvas = matrix.swap(canvas);
+        if panel.animate() {
+            changed = true;
         }
-        let ms = updated.elapsed().as_millis();
-        if ms < METER_UPDATE_MS {
-            thread::sleep(Duration::from_millis((METER_UPDATE_MS - ms).try_into().unwrap()));
+        // MIDI is handled as soon as it arrives, but drawing is limited to the update rate
+        let until_update = METER_UPDATE.saturating_sub(drawn.elapsed());
+        if changed && until_update.is_zero() {
+            display.set_brightness(panel_brightness(&panel, brightness));
+            panel.draw(display.canvas(), fonts);
+            display.swap();
+            drawn = Instant::now();
+            changed = false;
         }
+        timeout = if changed {
+            until_update
+        } else {
+            panel.next_animation().max(METER_UPDATE.saturating_sub(drawn.elapsed()))
+        }.min(METER_MAX_WAIT);
     }
-    canvas
-}
\ No newline at end of file
+    Ok(())
+}
@@ -1,120 +1,463 @@
-use rpi_led_matrix::{LedCanvas, LedColor};
-use wmidi::{U7, MidiMessage, ControlFunction};
-use crate::midi;
+use std::mem;
+use std::time::{Duration, Instant};
+use rpi_led_matrix::LedColor;
+use wmidi::{U7, MidiMessage, ControlFunction, Channel};
 use crate::helper::scale;
+use crate::config::Config;
+use crate::drums::DrumPads;
+use crate::chords::PitchClasses;
+use crate::pianoroll::PianoRoll;
+use crate::screen::{Screen, ScreenTrigger};
+use crate::clock::Clock;
+use crate::tempo::Tempo;
+use crate::display::Canvas;
+use crate::font::Fonts;
+use crate::idle::{Idle, IdleMode};
+use crate::banner::Banner;
+use crate::sysex::Command;
+use crate::log::{log_info, log_warn};
+use crate::mpe::{MpeZone, Rpn};
 
 use super::notes::NoteSlots;
 
 pub struct PanelMeter {
+    screens: Vec<Screen>,
+    screen: Screen,
+    screen_triggers: Vec<ScreenTrigger>,
+    chord_names: bool,
+    piano_roll: PianoRoll<{Self::MIDI_CHANNELS}>,
+    clock: Clock,
+    tempo: Tempo,
+    idle: Idle,
+    channels: [Channel; Self::MIDI_CHANNELS],
+    colors: [LedColor; Self::MIDI_CHANNELS],
+    expression_controls: [ControlFunction; Self::MIDI_CHANNELS],
+    damper_controls: [ControlFunction; Self::MIDI_CHANNELS],
     expression_cc: [U7; Self::MIDI_CHANNELS],
-    notes: NoteSlots<'static, {Self::NOTE_SLOTS}, {Self::MIDI_CHANNELS}>,
+    notes: NoteSlots<{Self::MIDI_CHANNELS}>,
+    drums: [Option<DrumPads>; Self::MIDI_CHANNELS],
     damper_cc: [bool; Self::MIDI_CHANNELS],
-    tick: usize
+    banner: Option<Banner>,
+    replies: Vec<Vec<u8>>,
+    sensing: Option<Instant>,
+    mpe: [MpeZone; Self::MIDI_CHANNELS],
+    rpn: Rpn
 }
 
 impl PanelMeter {
-    const MIDI_CHANNELS: usize = 3;
-    const NOTE_SLOTS: usize = 24;
+    pub const MIDI_CHANNELS: usize = 3;
 
-    pub fn new() -> Self {
-        let zero: U7 = 0.try_into().unwrap();
+    // pedals use the 3 columns on each side, with a gap before the notes in the middle
+    const FIRST_NOTE_COL: i32 = 4;
+    const NOTE_MARGIN: i32 = 8;
+
+    // a device which sends Active Sensing sends something at least every 300ms until it is disconnected
+    const SENSING_TIMEOUT: Duration = Duration::from_millis(300);
+
+    pub fn new(config: &Config, width: i32, height: i32) -> Self {
+        let note_slots = (width - Self::NOTE_MARGIN).max(1) as usize;
+        let mappings = config.channels.clone().map(|c| c.notes);
+        let mut notes = NoteSlots::new(mappings, note_slots);
+        notes.set_max_hold(config.max_hold);
+        notes.set_eviction(config.eviction);
         Self {
-            expression_cc: [zero; Self::MIDI_CHANNELS],
-            notes: NoteSlots::new(&Self::CH_COLORS),
+            screens: config.screens.clone(),
+            screen: config.screens[0],
+            screen_triggers: config.screen_triggers.clone(),
+            chord_names: config.chord_names,
+            piano_roll: PianoRoll::new(note_slots, height as usize),
+            clock: Clock::new(&config.clock),
+            tempo: Tempo::new(),
+            idle: Idle::new(config),
+            channels: config.channels.clone().map(|c| c.midi_channel),
+            colors: config.channels.clone().map(|c| c.color),
+            expression_controls: config.channels.clone().map(|c| c.expression_cc),
+            damper_controls: config.channels.clone().map(|c| c.damper_cc),
+            expression_cc: [U7::MIN; Self::MIDI_CHANNELS],
+            notes,
+            drums: config.channels.clone().map(|c| c.drums.map(DrumPads::new)),
             damper_cc: [false; Self::MIDI_CHANNELS],
-            tick: 0
+            banner: None,
+            replies: Vec::new(),
+            sensing: None,
+            mpe: config.channels.clone().map(|c| MpeZone::new(c.mpe)),
+            rpn: Rpn::new()
+        }
+    }
+
+    // apply a changed config, keeping the current notes and pedals
+    pub fn reconfigure(&mut self, config: &Config) {
+        if !config.screens.contains(&self.screen) {
+            self.screen = config.screens[0];
+        }
+        self.screens = config.screens.clone();
+        self.screen_triggers = config.screen_triggers.clone();
+        self.chord_names = config.chord_names;
+        self.idle.reconfigure(config);
+        self.clock.reconfigure(&config.clock);
+        self.channels = config.channels.clone().map(|c| c.midi_channel);
+        self.colors = config.channels.clone().map(|c| c.color);
+        self.expression_controls = config.channels.clone().map(|c| c.expression_cc);
+        self.damper_controls = config.channels.clone().map(|c| c.damper_cc);
+        self.notes.set_mappings(config.channels.clone().map(|c| c.notes));
+        self.notes.set_max_hold(config.max_hold);
+        self.notes.set_eviction(config.eviction);
+        for (zone, c) in self.mpe.iter_mut().zip(&config.channels) {
+            zone.reconfigure(c.mpe);
+        }
+        for (drums, c) in self.drums.iter_mut().zip(&config.channels) {
+            if drums.as_ref().map(|d| d.pads()) != c.drums.as_deref() {
+                *drums = c.drums.clone().map(DrumPads::new);
+            }
+        }
+    }
+
+    pub fn idle(&self) -> &Idle {
+        &self.idle
+    }
+
+    // true if the clock is shown, either as the screen or while idle (so it can be dimmed at night)
+    pub fn showing_clock(&self) -> bool {
+        match self.idle.mode() {
+            Some(IdleMode::Clock) => true,
+            Some(IdleMode::Blank) => false,
+            _ => self.banner.is_none() && self.screen == Screen::Clock
+        }
+    }
+
+    fn showing_piano_roll(&self) -> bool {
+        matches!(self.idle.mode(), None | Some(IdleMode::Dim)) && self.banner.is_none() && self.screen == Screen::PianoRoll
+    }
+
+    // SysEx to send back to the device, eg. in reply to a version query
+    pub fn take_replies(&mut self) -> Vec<Vec<u8>> {
+        mem::take(&mut self.replies)
+    }
+
+    // the meter channel showing a MIDI channel, including MPE member channels
+    fn channel(&self, ch: Channel) -> Option<usize> {
+        self.member(ch).or_else(|| self.manager(ch))
+    }
+
+    // the meter channel with this MIDI channel, unless an MPE zone is using it as a member channel
+    fn manager(&self, ch: Channel) -> Option<usize> {
+        if self.member(ch).is_some() {
+            return None;
+        }
+        self.channels.iter().position(|c| *c == ch)
+    }
+
+    fn member(&self, ch: Channel) -> Option<usize> {
+        (0..Self::MIDI_CHANNELS).find(|i| self.mpe[*i].is_member(self.channels[*i], ch))
+    }
+
+    pub fn animate(&mut self) -> bool {
+        let mut changed = false;
+        // the piano roll only scrolls while it's shown, catching up when it's shown again
+        if self.showing_piano_roll() {
+            let notes = &self.notes;
+            let rows = self.piano_roll.rows();
+            let sounding = notes.slots().flat_map(|slot| {
+                (0..Self::MIDI_CHANNELS)
+                    .filter(|c| slot.channels[*c] > U7::MIN)
+                    .filter_map(|c| notes.position(slot.note, c, rows).map(|row| (row, c)))
+            });
+            changed = self.piano_roll.step(sounding);
+        }
+        if self.clock.animate() && self.showing_clock() {
+            changed = true;
+        }
+        if self.idle.animate() {
+            changed = true;
+        }
+        if self.tempo.animate() && self.screen == Screen::Bpm {
+            changed = true;
+        }
+        for drums in self.drums.iter_mut().flatten() {
+            if drums.animate() {
+                changed = true;
+            }
+        }
+        if self.sensing.is_some_and(|last| last.elapsed() > Self::SENSING_TIMEOUT) {
+            // eg. the cable was pulled out mid-note, but the device is still open
+            log_warn!("Active Sensing stopped, clearing notes and pedals");
+            self.sensing = None;
+            for i in 0..Self::MIDI_CHANNELS {
+                self.notes.clear_channel(i);
+                self.reset_controllers(i);
+            }
+            changed = true;
+        }
+        if self.notes.animate() {
+            changed = true;
+        }
+        if self.banner.as_ref().is_some_and(|b| b.expired()) {
+            self.banner = None;
+            changed = true;
+        } else if self.banner.as_mut().is_some_and(|b| b.animate()) {
+            changed = true;
+        }
+        changed
+    }
+
+    // how long until animate might change something
+    pub fn next_animation(&self) -> Duration {
+        let clock = if self.showing_clock() { Some(self.clock.next_animation()) } else { None };
+        let drums = self.drums.iter().flatten().filter_map(|d| d.next_animation());
+        let banner = self.banner.as_ref().and_then(|b| b.next_animation());
+        let sensing = self.sensing.map(|last| Self::SENSING_TIMEOUT.saturating_sub(last.elapsed()) + Duration::from_millis(1));
+        let piano_roll = (self.showing_piano_roll() && !self.piano_roll.is_empty()).then(|| self.piano_roll.next_step());
+        [piano_roll, clock, self.tempo.next_animation(), self.idle.next_animation(), banner, sensing, self.notes.next_animation()].into_iter()
+            .flatten()
+            .chain(drums)
+            .min()
+            .unwrap_or(Duration::MAX)
+    }
+
+    // note that a message arrived, even if it is handled elsewhere (eg. the brightness CC)
+    pub fn activity(&mut self, message: &MidiMessage, received: Instant) {
+        // the clock and active sensing are sent constantly, even when nothing is being played
+        if !matches!(message, MidiMessage::TimingClock | MidiMessage::ActiveSensing) {
+            self.idle.wake();
+        }
+        // once Active Sensing has been received, anything arriving shows the device is still connected
+        if *message == MidiMessage::ActiveSensing || self.sensing.is_some() {
+            self.sensing = Some(received);
         }
     }
 
-    pub fn handle(&mut self, message: MidiMessage<'static>) {
+    pub fn handle(&mut self, message: MidiMessage<'static>, received: Instant) {
+        self.activity(&message, received);
+        for trigger in &self.screen_triggers {
+            if let Some(index) = trigger.selects(&message) {
+                if let Some(screen) = self.screens.get(index) {
+                    self.screen = *screen;
+                }
+                return;
+            }
+        }
+        if let Some((ch, parameter, value)) = self.rpn.handle(&message) {
+            for i in 0..Self::MIDI_CHANNELS {
+                if self.mpe[i].set_parameter(self.channels[i], ch, parameter, value) && parameter == Rpn::MPE_CONFIGURATION {
+                    log_info!("MPE zone on channel {} has {} member channels", ch.number(), value);
+                }
+            }
+        }
+        // pressure, pitch bend and timbre on an MPE member channel only apply to the note played on it
+        if let Some((ch, i)) = message.channel().and_then(|ch| self.member(ch).map(|i| (ch, i))) {
+            if let Some(expression) = self.mpe[i].handle(&message) {
+                if let Some(n) = self.mpe[i].note(ch) {
+                    self.notes.set_expression(n, i, expression);
+                }
+                return;
+            }
+        }
         match message {
             MidiMessage::TimingClock => {
-                self.tick = if self.tick == midi::TICKS_PER_BEAT {
-                    0
-                } else {
-                    self.tick + 1
-                };
-            },
-            MidiMessage::ControlChange(ch, ControlFunction::DAMPER_PEDAL, v) => {
-                let v_u8: u8 = v.into();
-                let damper = v_u8 > 64;
-                self.notes.set_damper(ch, damper);
-                let i: usize = ch.index() as usize;
-                if i < Self::MIDI_CHANNELS {
-                    self.damper_cc[i] = damper;
-                }
+                self.tempo.tick(received);
             },
-            MidiMessage::ControlChange(ch, ControlFunction::EXPRESSION_CONTROLLER, v) => {
-                let i = ch.index() as usize;
-                if i < Self::MIDI_CHANNELS {
-                    self.expression_cc[i] = v;
+            MidiMessage::ControlChange(ch, cc, v) => {
+                // pedals and channel mode messages are sent on the MPE manager channel, for the whole zone
+                if let Some(i) = self.manager(ch) {
+                    if cc == ControlFunction::ALL_SOUND_OFF {
+                        self.notes.clear_channel(i);
+                    } else if cc == ControlFunction::RESET_ALL_CONTROLLERS {
+                        self.reset_controllers(i);
+                    } else if cc >= ControlFunction::ALL_NOTES_OFF {
+                        // omni and mono/poly mode changes also turn all notes off
+                        self.notes.release_channel(i);
+                    } else if cc == self.damper_controls[i] {
+                        let v_u8: u8 = v.into();
+                        let damper = v_u8 > 64;
+                        self.notes.set_damper(i, damper);
+                        self.damper_cc[i] = damper;
+                    } else if cc == self.expression_controls[i] {
+                        self.expression_cc[i] = v;
+                    }
                 }
             },
             MidiMessage::NoteOn(ch, n, v) => {
-                self.notes.set_note(n, ch, v);
+                if let Some(i) = self.channel(ch) {
+                    if v > U7::MIN {
+                        if let Some(row) = self.notes.position(n, i, self.piano_roll.rows()) {
+                            self.piano_roll.add(row, i);
+                        }
+                    }
+                    if let Some(drums) = &mut self.drums[i] {
+                        drums.hit(n, v, received);
+                    } else {
+                        self.notes.set_note(n, i, v);
+                        if self.mpe[i].is_member(self.channels[i], ch) {
+                            // starting with any expression sent just before it
+                            self.mpe[i].set_note(ch, Some(n));
+                            self.notes.set_expression(n, i, self.mpe[i].expression(ch));
+                        }
+                    }
+                }
             },
             MidiMessage::NoteOff(ch, n, _) => {
-                self.notes.set_note(n, ch, U7::MIN);
+                if let Some(i) = self.channel(ch) {
+                    if self.mpe[i].note(ch) == Some(n) {
+                        self.mpe[i].set_note(ch, None);
+                    }
+                    if self.drums[i].is_some() {
+                        return;
+                    }
+                    // the note stays while another member channel is playing the same pitch, now with its expression
+                    if let Some(other) = self.mpe[i].is_member(self.channels[i], ch).then(|| self.mpe[i].playing(n)).flatten() {
+                        self.notes.set_expression(n, i, self.mpe[i].expression(other));
+                    } else {
+                        self.notes.set_note(n, i, U7::MIN);
+                    }
+                }
             },
             MidiMessage::PolyphonicKeyPressure(ch, n, v) => {
-                self.notes.set_note(n, ch, v);
+                if let Some(i) = self.channel(ch) {
+                    self.notes.set_pressure(n, i, v);
+                }
             },
             MidiMessage::ChannelPressure(ch, v) => {
-                self.notes.set_channel(ch, v);
+                if let Some(i) = self.channel(ch) {
+                    self.notes.set_channel_pressure(i, v);
+                }
+            },
+            MidiMessage::Reset => {
+                for i in 0..Self::MIDI_CHANNELS {
+                    self.notes.clear_channel(i);
+                    self.reset_controllers(i);
+                }
+            },
+            MidiMessage::OwnedSysEx(_) => {
+                if let Some(command) = Command::parse(&message) {
+                    self.command(command);
+                }
             },
             _ => { }
         }
     }
 
-    const CH_COLORS: [LedColor; Self::MIDI_CHANNELS] = [
-        LedColor { red: 255, green: 0, blue: 0 },
-        LedColor { red: 0, green: 255, blue: 0 },
-        LedColor { red: 0, green: 0, blue: 255 }
-    ];
+    fn reset_controllers(&mut self, i: usize) {
+        // the pedals' positions are unknown until they next move, so hide them
+        self.notes.set_damper(i, false);
+        self.damper_cc[i] = false;
+        self.expression_cc[i] = U7::MIN;
+    }
+
+    fn command(&mut self, command: Command) {
+        log_info!("SysEx command {:?}", command);
+        match command {
+            Command::Text(_, text) if text.is_empty() => self.banner = None,
+            Command::Text(duration, text) => self.banner = Some(Banner::new(text, duration)),
+            Command::Screen(index) => {
+                if let Some(screen) = self.screens.get(index) {
+                    self.screen = *screen;
+                }
+            },
+            Command::Color(i, color) => {
+                if let Some(c) = self.colors.get_mut(i) {
+                    *c = color;
+                }
+            },
+            Command::Brightness(_) => {
+                // handled with the brightness CC, before reaching the panel
+            },
+            Command::Version => self.replies.push(Command::version_reply())
+        }
+    }
 
     const FLASH: LedColor = LedColor { red: 255, green: 255, blue: 255 };
 
-    pub fn draw(&self, canvas: &mut LedCanvas) {
+    pub fn draw(&self, canvas: &mut dyn Canvas, fonts: &Fonts) {
         canvas.clear();
+        match self.idle.mode() {
+            Some(IdleMode::Blank) => return,
+            Some(IdleMode::Clock) => return self.clock.draw_shifted(canvas, fonts),
+            _ => { }
+        }
+        if let Some(banner) = &self.banner {
+            return banner.draw(canvas, fonts);
+        }
+        match self.screen {
+            Screen::Clock => self.clock.draw(canvas, fonts),
+            Screen::Bpm => self.draw_bpm(canvas, fonts),
+            _ => self.draw_meter(canvas, fonts)
+        }
+    }
+
+    fn draw_bpm(&self, canvas: &mut dyn Canvas, fonts: &Fonts) {
+        let text = match self.tempo.bpm() {
+            Some(bpm) => format!("{:.0}", bpm),
+            None => "---".to_string()
+        };
+        let (width, height) = canvas.size();
+        let font = fonts.fitting(&text, width - 6, height);
+        let x = (width - font.width(&text)) / 2;
+        font.draw(canvas, &text, x, font.middle(height), &Self::FLASH);
+        self.draw_beat(canvas, width - 3);
+    }
+
+    fn draw_meter(&self, canvas: &mut dyn Canvas, fonts: &Fonts) {
+        let width = canvas.size().0;
+        let note_slots = (width - Self::NOTE_MARGIN).max(1) as usize;
         // LHS expression pedal
         const FIRST_EXP_COL: i32 = 0;
         for i in 0..self.expression_cc.len() {
-            Self::draw_value(canvas, self.expression_cc[i], FIRST_EXP_COL + i as i32, &Self::CH_COLORS[i]);
+            Self::draw_value(canvas, self.expression_cc[i], FIRST_EXP_COL + i as i32, &self.colors[i]);
         }
         // notes in the middle
-        const FIRST_NOTE_COL: i32 = 4;
-        self.notes.draw(canvas, FIRST_NOTE_COL);
+        match self.screen {
+            Screen::Chords => {
+                let fonts = if self.chord_names { Some(fonts) } else { None };
+                PitchClasses::new(self.notes.slots()).draw(canvas, Self::FIRST_NOTE_COL, note_slots, &self.colors, fonts);
+            },
+            Screen::PianoRoll => self.piano_roll.draw(canvas, Self::FIRST_NOTE_COL, &self.colors),
+            _ => self.notes.draw(canvas, Self::FIRST_NOTE_COL, &self.colors)
+        }
+        // drum hits over the top of the notes
+        for i in 0..self.drums.len() {
+            if let Some(drums) = &self.drums[i] {
+                drums.draw(canvas, Self::FIRST_NOTE_COL, note_slots, &self.colors[i]);
+            }
+        }
         // RHS damper pedal
-        const FIRST_DAMP_COL: i32 = 29;
+        let first_damp_col = width - 3;
         for i in 0..self.damper_cc.len() {
-            Self::draw_bool(canvas, self.damper_cc[i], FIRST_DAMP_COL + i as i32, &Self::CH_COLORS[i]);
+            Self::draw_bool(canvas, self.damper_cc[i], first_damp_col + i as i32, &self.colors[i]);
         }
+        self.draw_beat(canvas, first_damp_col);
+    }
+
+    fn draw_beat(&self, canvas: &mut dyn Canvas, first_column: i32) {
         // top right corner flash on beat
-        if self.tick < 6 {
-            for x in FIRST_DAMP_COL..canvas.canvas_size().0 {
-                canvas.draw_line(x, 0, x, 2, &Self::FLASH);
+        if self.tempo.tick < 6 {
+            let (width, height) = canvas.size();
+            for x in first_column..width {
+                canvas.draw_line(x, 0, x, height * 3 / 16 - 1, &Self::FLASH);
             }
         }
     }
 
-    fn draw_bool(canvas: &mut LedCanvas, b: bool, x: i32, color: &LedColor) {
+    fn draw_bool(canvas: &mut dyn Canvas, b: bool, x: i32, color: &LedColor) {
         if b {
-            canvas.draw_line(x, 4, x, 15, color)
+            let height = canvas.size().1;
+            canvas.draw_line(x, height / 4, x, height - 1, color)
         }
     }
 
-    fn draw_value(canvas: &mut LedCanvas, value: U7, x: i32, color: &LedColor) {
+    fn draw_value(canvas: &mut dyn Canvas, value: U7, x: i32, color: &LedColor) {
         let v: u8 = value.into();
+        let height = canvas.size().1;
         if v == 127 {
-            canvas.draw_line(x, 0, x, 15, color);
+            canvas.draw_line(x, 0, x, height - 1, color);
         } else {
-            let full_pixels = v as i32 / 8;
-            let last_pixel = v % 8 * 32;
+            let scaled = v as i32 * height;
+            let full_pixels = scaled / 128;
+            let last_pixel = (scaled % 128 * 2) as u8;
             if full_pixels > 0 {
-                canvas.draw_line(x, 16 - full_pixels, x, 15, color)
+                canvas.draw_line(x, height - full_pixels, x, height - 1, color)
             }
             if last_pixel > 0 {
-                canvas.set(x, 15 - full_pixels, &scale(color, last_pixel))
+                canvas.set(x, height - 1 - full_pixels, &scale(color, last_pixel))
             }
         }
     }
@@ -1,71 +1,389 @@
 use std::collections::VecDeque;
+use std::ffi::{c_int, c_short, c_ulong};
 use std::fs;
-use std::error::Error;
+use std::io::{self, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::path::Path;
 use wmidi::FromBytesError;
 use wmidi::MidiMessage;
 use wmidi::U7;
 use nonblock::NonBlockingReader;
 
+use crate::error::{Error, Result};
+use crate::log::{log_debug, log_warn};
+
 pub const TICKS_PER_BEAT: usize = 24;
 
+pub struct MidiDevice {
+    pub path: String,
+    pub name: String
+}
+
+impl MidiDevice {
+    pub fn list() -> Result<Vec<Self>> {
+        let mut devices = Vec::new();
+        for path in list_files("/dev", "midi")? {
+            let name = Self::card_name(&path).unwrap_or_else(|| "Unknown".to_string());
+            devices.push(Self { path, name });
+        }
+        Ok(devices)
+    }
+
+    // find a device by path or by part of its name, otherwise the first device
+    pub fn find(pattern: Option<&str>) -> Result<Option<Self>> {
+        if let Some(path) = pattern.filter(|p| p.starts_with('/')) {
+            // wait for the device to be plugged in
+            if !Path::new(path).exists() {
+                return Ok(None);
+            }
+            let name = Self::card_name(path).unwrap_or_else(|| "Unknown".to_string());
+            return Ok(Some(Self { path: path.to_string(), name }));
+        }
+        let devices = Self::list()?;
+        Ok(match pattern {
+            None => devices.into_iter().next(),
+            Some(pattern) => {
+                let pattern = pattern.to_lowercase();
+                devices.into_iter().find(|d| d.name.to_lowercase().contains(&pattern) || d.path.contains(&pattern))
+            }
+        })
+    }
+
+    fn card_name(path: &str) -> Option<String> {
+        // each /dev/midi* belongs to a sound card, which has a human readable name
+        let file = Path::new(path).file_name()?.to_string_lossy().to_string();
+        let number = fs::read_to_string(format!("/sys/class/sound/{}/device/number", file)).ok()?;
+        let cards = fs::read_to_string("/proc/asound/cards").ok()?;
+        for line in cards.lines() {
+            // eg. " 1 [Mini32         ]: USB-Audio - Keystation Mini 32"
+            if let Some((index, rest)) = line.trim_start().split_once(' ') {
+                if index == number.trim() {
+                    return rest.split_once(" - ").map(|(_, name)| name.trim().to_string());
+                }
+            }
+        }
+        None
+    }
+}
+
+fn list_files(root: &str, prefix: &str) -> Result<Vec<String>> {
+    let error = |e| Error::Midi(format!("Cannot list '{}': {}", root, e));
+    let md = fs::metadata(root).map_err(error)?;
+    if md.is_dir() {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(root).map_err(error)? {
+            let path = entry.map_err(error)?.path();
+            if !path.is_dir() && path.file_name().is_some_and(|f| f.to_string_lossy().starts_with(prefix)) {
+                files.push(path.display().to_string());
+            }
+        }
+        files.sort();
+        Ok(files)
+    } else {
+        Ok(vec![root.to_string()])
+    }
+}
+
+#[repr(C)]
+struct PollFd {
+    fd: c_int,
+    events: c_short,
+    revents: c_short
+}
+
+const POLLIN: c_short = 1;
+const O_NONBLOCK: c_int = 0o4000;
+
+extern "C" {
+    fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
+
 pub struct NonBlockingInputDevice {
     reader: NonBlockingReader<fs::File>,
+    fd: RawFd,
+    buf: Vec<u8>,
     bytes: Vec<u8>,
     messages: VecDeque<MidiMessage<'static>>,
     include_clock_ticks: bool,
-    rewrite_note_zero_as_off: bool
+    rewrite_note_zero_as_off: bool,
+    skipping_sysex: bool
 }
 
 impl NonBlockingInputDevice {
+    const FIRST_STATUS: u8 = 0x80;
+    const SYSEX_START: u8 = 0xF0;
+    const SYSEX_END: u8 = 0xF7;
+    const FIRST_REALTIME: u8 = 0xF8;
+    const MAX_SYSEX_BYTES: usize = 256;
+
     pub fn is_connected(&self) -> bool {
         !self.reader.is_eof()
     }
 
-    pub fn open(midi_in: &str, include_clock_ticks: bool) -> Result<Self, Box<dyn Error>> {
-        let input = fs::File::options().read(true).open(midi_in).map_err(|e| format!("Cannot open MIDI IN '{}': {}", midi_in, e))?;
-        let reader = NonBlockingReader::from_fd(input)?;
+    pub fn open(midi_in: &str, include_clock_ticks: bool) -> Result<Self> {
+        let error = |e| Error::Midi(format!("Cannot open MIDI IN '{}': {}", midi_in, e));
+        let input = fs::File::options().read(true).open(midi_in).map_err(error)?;
+        let fd = input.as_raw_fd();
+        let reader = NonBlockingReader::from_fd(input).map_err(error)?;
         Ok(Self {
             reader,
+            fd,
+            buf: Vec::new(),
             bytes: Vec::new(),
             messages: VecDeque::new(),
             include_clock_ticks,
-            rewrite_note_zero_as_off: true
+            rewrite_note_zero_as_off: true,
+            skipping_sysex: false
         })
     }
 
-    pub fn read(&mut self) -> Result<Option<MidiMessage<'static>>, Box<dyn Error>> {
-        let mut buf = Vec::new();
-        self.reader.read_available(&mut buf)?;
-        for byte in buf {
-            self.process(byte);
+    pub fn read(&mut self) -> Result<Option<MidiMessage<'static>>> {
+        if self.messages.is_empty() {
+            // reuse the buffer, rather than allocating for every read
+            let mut buf = mem::take(&mut self.buf);
+            buf.clear();
+            let result = self.reader.read_available(&mut buf);
+            for byte in &buf {
+                self.process(*byte);
+            }
+            self.buf = buf;
+            result.map_err(|e| Error::Midi(format!("Cannot read MIDI IN: {}", e)))?;
         }
         Ok(self.messages.pop_front())
     }
 
+    // block until there is something to read (or the device disconnects), the timeout passes, or a signal arrives
+    pub fn wait(&self, timeout: Duration) -> Result<()> {
+        if !self.messages.is_empty() {
+            return Ok(());
+        }
+        let mut fds = PollFd { fd: self.fd, events: POLLIN, revents: 0 };
+        let ms = timeout.as_millis().min(c_int::MAX as u128) as c_int;
+        if unsafe { poll(&mut fds, 1, ms) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(Error::Midi(format!("Cannot wait for MIDI IN: {}", err)));
+            }
+        }
+        Ok(())
+    }
+
     fn process(&mut self, byte: u8) {
+        if byte >= Self::FIRST_REALTIME {
+            // realtime messages can arrive in the middle of any other message, including SysEx
+            if let Ok(message) = MidiMessage::try_from([byte].as_slice()) {
+                self.complete(message.to_owned());
+            }
+            return;
+        }
+        if self.skipping_sysex {
+            if byte < Self::FIRST_STATUS {
+                // still in the SysEx which was too long
+                return;
+            }
+            self.skipping_sysex = false;
+            if byte == Self::SYSEX_END {
+                return;
+            }
+        }
+        if byte >= Self::FIRST_STATUS && byte != Self::SYSEX_END && !self.bytes.is_empty() {
+            // a new status byte means the message so far was cut short (eg. a truncated SysEx dump)
+            log_warn!("Incomplete MIDI message {:02X?}", &self.bytes[..self.bytes.len().min(8)]);
+            self.bytes.clear();
+        }
         self.bytes.push(byte);
+        if self.bytes[0] == Self::SYSEX_START && self.bytes.len() > Self::MAX_SYSEX_BYTES {
+            // eg. a bulk backup from a keyboard, which isn't useful and would otherwise keep growing
+            log_warn!("Ignoring SysEx longer than {} bytes", Self::MAX_SYSEX_BYTES);
+            self.bytes.clear();
+            self.skipping_sysex = true;
+            return;
+        }
         match MidiMessage::try_from(self.bytes.as_slice()) {
-            Ok(MidiMessage::TimingClock) if !self.include_clock_ticks => {
-                // skip clock tick if not required
-                self.bytes.clear();
-            },
-            Ok(MidiMessage::NoteOn(c, n, U7::MIN)) if self.rewrite_note_zero_as_off => {
-                // some keyboards send NoteOn(velocity: 0) instead of NoteOff (eg. Kaysound MK-4902)
-                self.messages.push_back(MidiMessage::NoteOff(c, n, U7::MIN));
-                self.bytes.clear();
-            },
             Ok(message) => {
                 // message complete
-                self.messages.push_back(message.to_owned());
+                let message = message.to_owned();
                 self.bytes.clear();
+                self.complete(message);
             },
             Err(FromBytesError::NoBytes) | Err(FromBytesError::NoSysExEndByte) | Err(FromBytesError::NotEnoughBytes) => {
                 // wait for more bytes
             }, 
-            _ => {
+            Err(err) => {
                 // invalid message, clear and wait for next message
+                // (only logged for debugging, as a device using running status sends data bytes without a status byte all the time)
+                log_debug!("Invalid MIDI message {:02X?}: {:?}", self.bytes, err);
                 self.bytes.clear();
             }
         }
     }
-}
\ No newline at end of file
+
+    fn complete(&mut self, message: MidiMessage<'static>) {
+        match message {
+            MidiMessage::TimingClock if !self.include_clock_ticks => {
+                // skip clock tick if not required
+            },
+            MidiMessage::NoteOn(c, n, U7::MIN) if self.rewrite_note_zero_as_off => {
+                // some keyboards send NoteOn(velocity: 0) instead of NoteOff (eg. Kaysound MK-4902)
+                self.messages.push_back(MidiMessage::NoteOff(c, n, U7::MIN));
+            },
+            message => self.messages.push_back(message)
+        }
+    }
+}
+
+pub struct MidiOutput {
+    file: fs::File,
+    // the end of a message the device wasn't ready for, sent before anything else
+    unsent: Vec<u8>
+}
+
+impl MidiOutput {
+    // non-blocking, so a device which stops reading can't hold up the panel
+    pub fn open(midi_out: &str) -> Result<Self> {
+        let file = fs::File::options().write(true).custom_flags(O_NONBLOCK).open(midi_out)
+            .map_err(|e| Error::Midi(format!("Cannot open MIDI OUT '{}': {}", midi_out, e)))?;
+        Ok(Self { file, unsent: Vec::new() })
+    }
+
+    // true if sent (or at least started, with the rest sent later), or false if dropped because the device's buffer is full
+    pub fn send(&mut self, bytes: &[u8]) -> Result<bool> {
+        // only whole messages are dropped, as a partial one (eg. a SysEx without its F7) would confuse the device
+        if !self.flush()? {
+            return Ok(false);
+        }
+        let written = Self::write(&mut self.file, bytes)?;
+        if written == 0 {
+            return Ok(false);
+        }
+        self.unsent.extend_from_slice(&bytes[written..]);
+        Ok(true)
+    }
+
+    // send the rest of a message which was partly sent, returning true once there's nothing left
+    pub fn flush(&mut self) -> Result<bool> {
+        if !self.unsent.is_empty() {
+            let written = Self::write(&mut self.file, &self.unsent)?;
+            self.unsent.drain(..written);
+        }
+        Ok(self.unsent.is_empty())
+    }
+
+    // the number of bytes the device accepted
+    fn write(file: &mut fs::File, bytes: &[u8]) -> Result<usize> {
+        match file.write(bytes) {
+            Ok(written) => Ok(written),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(Error::Midi(format!("Cannot write MIDI OUT: {}", e)))
+        }
+    }
+}
+
+pub type Received = (Instant, MidiMessage<'static>);
+
+// reads MIDI on its own thread, so each message is timestamped when it arrives rather than when it is handled,
+// and sends any replies back to the same device
+pub struct MidiConnection {
+    receiver: Receiver<Received>,
+    output: Option<MidiOutput>,
+    dropped: Arc<AtomicUsize>,
+    reported: usize,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<Result<()>>>
+}
+
+impl MidiConnection {
+    const QUEUE_SIZE: usize = 1024;
+    const STOP_CHECK: Duration = Duration::from_millis(100);
+
+    pub fn start(device: NonBlockingInputDevice, output: Option<MidiOutput>) -> Self {
+        // a bounded std channel is a lock-free array queue
+        let (sender, receiver) = mpsc::sync_channel(Self::QUEUE_SIZE);
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let dropped = dropped.clone();
+            let stop = stop.clone();
+            thread::spawn(move || Self::run(device, sender, &dropped, &stop))
+        };
+        Self {
+            receiver,
+            output,
+            dropped,
+            reported: 0,
+            stop,
+            thread: Some(thread)
+        }
+    }
+
+    fn run(mut device: NonBlockingInputDevice, sender: SyncSender<Received>, dropped: &AtomicUsize, stop: &AtomicBool) -> Result<()> {
+        while device.is_connected() && !stop.load(Ordering::SeqCst) {
+            device.wait(Self::STOP_CHECK)?;
+            while let Some(message) = device.read()? {
+                match sender.try_send((Instant::now(), message)) {
+                    Ok(()) => { },
+                    Err(TrySendError::Full(_)) => {
+                        dropped.fetch_add(1, Ordering::SeqCst);
+                    },
+                    Err(TrySendError::Disconnected(_)) => return Ok(())
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the next message, waiting up to the timeout for one to arrive, or None if the device disconnected
+    pub fn receive(&mut self, timeout: Duration) -> Result<Option<Received>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(received) => Ok(Some(received)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                // the thread has finished, either by disconnecting or with an error
+                match self.thread.take().map(|t| t.join()) {
+                    Some(Ok(result)) => result.map(|_| None),
+                    Some(Err(payload)) => Err(Error::from_panic(payload)),
+                    None => Ok(None)
+                }
+            }
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.thread.is_some()
+    }
+
+    // send a message to the device, if it has MIDI OUT
+    pub fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        match &mut self.output {
+            Some(output) => match output.send(bytes)? {
+                true => Ok(()),
+                false => Err(Error::Midi("MIDI OUT is busy".to_string()))
+            },
+            None => Err(Error::Midi("No MIDI OUT to send to".to_string()))
+        }
+    }
+
+    // the number of messages dropped because the queue was full, since this was last called
+    pub fn dropped(&mut self) -> usize {
+        let dropped = self.dropped.load(Ordering::SeqCst);
+        let new = dropped - self.reported;
+        self.reported = dropped;
+        new
+    }
+}
+
+impl Drop for MidiConnection {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use wmidi::{Channel, ControlFunction, MidiMessage, Note};
+
+use crate::notes::Expression;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MpeConfig {
+    /// Each MIDI channel is separate
+    Off,
+    /// Use the member channels set by an MPE Configuration Message from the device
+    Auto,
+    /// The given number of member channels follow the manager channel
+    Members(u8)
+}
+
+// an MPE zone, with each note played on its own member channel next to the manager channel
+// (above it for the lower zone, managed from channel 1, or below it for the upper zone, managed from channel 16)
+pub struct MpeZone {
+    auto: bool,
+    members: u8,
+    bend_range: u8,
+    channels: [Member; 16]
+}
+
+// the note playing on a member channel, and the expression which applies to it
+#[derive(Copy, Clone, Default)]
+struct Member {
+    note: Option<Note>,
+    expression: Expression
+}
+
+impl MpeZone {
+    // the MPE default is ±48 semitones on member channels
+    const DEFAULT_BEND_RANGE: u8 = 48;
+    const MAX_MEMBERS: u8 = 15;
+
+    pub fn new(config: MpeConfig) -> Self {
+        let mut zone = Self {
+            auto: false,
+            members: 0,
+            bend_range: Self::DEFAULT_BEND_RANGE,
+            channels: [Member::default(); 16]
+        };
+        zone.reconfigure(config);
+        zone
+    }
+
+    // apply a changed config, keeping any members set by the device if still auto
+    pub fn reconfigure(&mut self, config: MpeConfig) {
+        match config {
+            MpeConfig::Off => {
+                self.auto = false;
+                self.members = 0;
+            },
+            MpeConfig::Auto if self.auto => { },
+            MpeConfig::Auto => {
+                self.auto = true;
+                self.members = 0;
+            },
+            MpeConfig::Members(members) => {
+                self.auto = false;
+                self.members = members.min(Self::MAX_MEMBERS);
+            }
+        }
+    }
+
+    // true if the channel is one of the zone's member channels
+    pub fn is_member(&self, manager: Channel, ch: Channel) -> bool {
+        let (manager, ch) = (manager.index(), ch.index());
+        if manager == Channel::Ch16.index() {
+            ch < manager && manager - ch <= self.members
+        } else {
+            ch > manager && ch - manager <= self.members
+        }
+    }
+
+    // apply a registered parameter sent to the zone, returning true if it was for this zone
+    pub fn set_parameter(&mut self, manager: Channel, ch: Channel, parameter: u16, value: u8) -> bool {
+        match parameter {
+            Rpn::MPE_CONFIGURATION if ch == manager && self.auto => {
+                self.members = value.min(Self::MAX_MEMBERS);
+                true
+            },
+            Rpn::PITCH_BEND_SENSITIVITY if self.is_member(manager, ch) => {
+                self.bend_range = value;
+                true
+            },
+            _ => false
+        }
+    }
+
+    // the note playing on a member channel, which its pressure, pitch bend and timbre apply to
+    pub fn note(&self, ch: Channel) -> Option<Note> {
+        self.channels[ch.index() as usize].note
+    }
+
+    pub fn expression(&self, ch: Channel) -> Expression {
+        self.channels[ch.index() as usize].expression
+    }
+
+    pub fn set_note(&mut self, ch: Channel, note: Option<Note>) {
+        self.channels[ch.index() as usize].note = note;
+    }
+
+    // a member channel still playing the note, as the same pitch can be played on several at once
+    pub fn playing(&self, note: Note) -> Option<Channel> {
+        let i = self.channels.iter().position(|m| m.note == Some(note))?;
+        Channel::from_index(i as u8).ok()
+    }
+
+    // apply pressure, pitch bend or timbre sent on a member channel, returning the expression if it changed
+    // (these are often sent just before a note starts, so are kept for it)
+    pub fn handle(&mut self, message: &MidiMessage) -> Option<Expression> {
+        let (ch, expression) = match message {
+            MidiMessage::ChannelPressure(ch, v) => (ch, Expression { pressure: *v, ..self.expression(*ch) }),
+            MidiMessage::PitchBendChange(ch, bend) => (ch, Expression { bend: semitones((*bend).into(), self.bend_range), ..self.expression(*ch) }),
+            MidiMessage::ControlChange(ch, TIMBRE, v) => (ch, Expression { timbre: Some(*v), ..self.expression(*ch) }),
+            _ => return None
+        };
+        self.channels[ch.index() as usize].expression = expression;
+        Some(expression)
+    }
+}
+
+// tracks the registered parameter selected on each channel, to notice when one is set
+pub struct Rpn {
+    selected: [(u8, u8); 16]
+}
+
+impl Rpn {
+    pub const PITCH_BEND_SENSITIVITY: u16 = 0;
+    pub const MPE_CONFIGURATION: u16 = 6;
+
+    const NULL: (u8, u8) = (127, 127);
+
+    pub fn new() -> Self {
+        Self {
+            selected: [Self::NULL; 16]
+        }
+    }
+
+    // the channel, parameter and value, if this message sets a registered parameter
+    pub fn handle(&mut self, message: &MidiMessage) -> Option<(Channel, u16, u8)> {
+        let MidiMessage::ControlChange(ch, cc, v) = message else {
+            return None;
+        };
+        let v: u8 = (*v).into();
+        let selected = &mut self.selected[ch.index() as usize];
+        match *cc {
+            ControlFunction::REGISTERED_PARAMETER_NUMBER_MSB => selected.0 = v,
+            ControlFunction::REGISTERED_PARAMETER_NUMBER_LSB => selected.1 = v,
+            ControlFunction::DATA_ENTRY_MSB if *selected != Self::NULL => {
+                return Some((*ch, (selected.0 as u16) << 7 | selected.1 as u16, v));
+            },
+            _ => { }
+        }
+        None
+    }
+}
+
+// pitch bend as semitones, given the range of the full bend
+fn semitones(bend: u16, range: u8) -> f32 {
+    (bend as f32 - 8192.0) / 8192.0 * range as f32
+}
+
+// timbre is sent as CC 74 on member channels
+pub const TIMBRE: ControlFunction = ControlFunction::SOUND_CONTROLLER_5;
@@ -1,14 +1,29 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
-use rpi_led_matrix::{LedCanvas, LedColor};
-use wmidi::{Note, Velocity, Channel, U7};
+use rpi_led_matrix::LedColor;
+use wmidi::{Note, Velocity, U7};
 
 use crate::helper::{add_assign, scale};
+use crate::display::Canvas;
+use crate::log::log_warn;
 
 #[derive(Debug)]
 pub struct NoteSlot<const C: usize> {
     pub note: Note,
-    pub channels: [Velocity; C]
+    pub channels: [Velocity; C],
+    held: [Instant; C],
+    struck: Instant,
+    expression: [Expression; C]
+}
+
+// how a note is shaped after being struck: aftertouch pressure, or with MPE also pitch bend and timbre
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Expression {
+    pub pressure: U7,
+    /// in semitones
+    pub bend: f32,
+    pub timbre: Option<U7>
 }
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -16,37 +31,136 @@ enum Direction {
     None, Up, Down
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum NoteMapping {
+    /// Scale the notes from lowest to highest (inclusive) across all slots
+    Linear(Note, Note),
+    /// Scale the range of notes played so far across all slots
+    Auto,
+    /// One slot per semitone, starting from the given note
+    Fixed(Note)
+}
+
+impl NoteMapping {
+    pub const PIANO: NoteMapping = NoteMapping::Linear(Note::A0, Note::C8);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Eviction {
+    /// Replace the note struck longest ago
+    Oldest,
+    /// Replace the note struck most softly
+    Quietest,
+    /// Replace the oldest note only held by the damper, otherwise the oldest note
+    Damper,
+    /// Replace the oldest note only held by the damper, otherwise don't show the new note
+    Never
+}
+
+impl Eviction {
+    pub const ALL: [Eviction; 4] = [Eviction::Oldest, Eviction::Quietest, Eviction::Damper, Eviction::Never];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Eviction::Oldest => "oldest",
+            Eviction::Quietest => "quietest",
+            Eviction::Damper => "damper",
+            Eviction::Never => "never"
+        }
+    }
+}
+
 impl<const C: usize> NoteSlot<C> {
     pub fn new(n: Note) -> Self {
         Self {
             note: n,
-            channels: [U7::MIN; C]
+            channels: [U7::MIN; C],
+            held: [Instant::now(); C],
+            struck: Instant::now(),
+            expression: [Expression::default(); C]
         }
     }
 
     const BLANK: LedColor = LedColor { red: 0, green: 0, blue: 0 };
+    const WHITE: LedColor = LedColor { red: 255, green: 255, blue: 255 };
+    const MAX_BEND_COLUMNS: i32 = 3;
+
+    // set the velocity on a channel, timing how long the note is held from when it was last struck
+    // (not when it is only being re-applied, eg. as the damper is released)
+    fn set(&mut self, c: usize, v: Velocity, restrike: bool) {
+        if v > U7::MIN {
+            if restrike {
+                self.held[c] = Instant::now();
+                self.struck = self.held[c];
+            }
+        } else {
+            self.expression[c] = Expression::default();
+        }
+        self.channels[c] = v;
+    }
+
+    fn set_pressure(&mut self, c: usize, v: U7, key_held: bool) {
+        if self.channels[c] > U7::MIN {
+            // pressure on this key shows it is still held, so it isn't stuck
+            // (but channel pressure could come from any other key)
+            if key_held {
+                self.held[c] = Instant::now();
+            }
+            self.expression[c].pressure = v;
+        }
+    }
+
+    // the channel's colour, whitened by the note's timbre
+    fn color(&self, c: usize, colors: &[LedColor; C]) -> LedColor {
+        let mut color = colors[c];
+        if let Some(timbre) = self.expression[c].timbre {
+            let timbre: u8 = timbre.into();
+            add_assign(&mut color, &scale(&Self::WHITE, timbre));
+        }
+        color
+    }
 
-    pub fn draw(&self, canvas: &mut LedCanvas, x: i32, colors: &[LedColor; C]) {
+    // draw the channels' velocities as stacked bars, except any hidden
+    pub fn draw(&self, canvas: &mut dyn Canvas, x: i32, colors: &[LedColor; C], hidden: [bool; C]) {
+        let height = canvas.size().1 as usize;
         let mut full_pixels = [0; C];
         let mut last_pixel = [0; C];
-        for i in 0..C {
+        for i in (0..C).filter(|i| !hidden[*i]) {
             let v: u8 = self.channels[i].into();
-            full_pixels[i] = v as usize / 8;
-            last_pixel[i] = v % 8 * 32;
+            let scaled = v as usize * height;
+            full_pixels[i] = scaled / 128;
+            last_pixel[i] = (scaled % 128 * 2) as u8;
         }
-        for led in 0..16 {
+        for led in 0..height {
             let scales = Self::scales(led, &full_pixels, &last_pixel);
             let mut color: LedColor = Self::BLANK;
-            for i in 0..C {
-                if scales[i] > 0 {
-                    if scales[i] < 255 {
-                        add_assign(&mut color, &scale(&colors[i], scales[i]));
+            for (i, s) in scales.into_iter().enumerate() {
+                if s > 0 {
+                    if s < 255 {
+                        add_assign(&mut color, &scale(&self.color(i, colors), s));
                     } else {
-                        add_assign(&mut color, &colors[i]);
+                        add_assign(&mut color, &self.color(i, colors));
                     }
                 }
             }
-            canvas.set(x, 15 - led as i32, &color);
+            canvas.set(x, (height - 1 - led) as i32, &color);
+        }
+    }
+
+    // draw a brighter pixel at the height of the note's pressure, moved sideways by its pitch bend (drawn after all the bars, as it can overlap the next)
+    pub fn draw_expression(&self, canvas: &mut dyn Canvas, x: i32, colors: &[LedColor; C], hidden: [bool; C]) {
+        let height = canvas.size().1;
+        for i in (0..C).filter(|i| !hidden[*i] && self.channels[*i] > U7::MIN) {
+            let Expression { pressure, bend, .. } = self.expression[i];
+            if pressure == U7::MIN && bend.abs() < 0.5 {
+                continue;
+            }
+            let level: u8 = if pressure > U7::MIN { pressure.into() } else { self.channels[i].into() };
+            let y = height - 1 - (level as i32 * height / 128).min(height - 1);
+            let offset = (bend.round() as i32).clamp(-Self::MAX_BEND_COLUMNS, Self::MAX_BEND_COLUMNS);
+            let mut color = self.color(i, colors);
+            add_assign(&mut color, &scale(&Self::WHITE, 128));
+            canvas.set(x + offset, y, &color);
         }
     }
 
@@ -67,51 +181,203 @@ impl<const C: usize> NoteSlot<C> {
     pub fn is_empty(&self) -> bool {
         self.channels.iter().all(|v| *v == U7::MIN)
     }
+
+    // how long the note has been held on the channel, if it is
+    fn held(&self, c: usize) -> Option<Duration> {
+        if self.channels[c] > U7::MIN {
+            Some(self.held[c].elapsed())
+        } else {
+            None
+        }
+    }
 }
 
-pub struct NoteSlots<'a, const N: usize, const C: usize> {
-    slots: [Option<NoteSlot<C>>; N],
-    colors: &'a [LedColor; C],
+pub struct NoteSlots<const C: usize> {
+    slots: Vec<Option<NoteSlot<C>>>,
+    mappings: [NoteMapping; C],
+    played: [Option<(Note, Note)>; C],
     damper: [bool; C],
-    when_damper_released: [HashMap<Note, Velocity>; C]
+    when_damper_released: [HashMap<Note, Velocity>; C],
+    eviction: Eviction,
+    hidden: [HashSet<Note>; C],
+    max_hold: Option<Duration>,
+    blink: Option<bool>,
+    created: Instant
 }
 
-impl<'a, const N: usize, const C: usize> NoteSlots<'a, N, C> {
-    pub fn new(colors: &'a [LedColor; C]) -> Self {
+impl<const C: usize> NoteSlots<C> {
+    pub fn new(mappings: [NoteMapping; C], size: usize) -> Self {
         let mut slots = Vec::new();
-        for _ in 0..N {
+        for _ in 0..size {
             slots.push(None);
         }
-        let mut when_damper_released = Vec::new();
-        for _ in 0..C {
-            when_damper_released.push(HashMap::new());
-        }
         Self {
-            slots: slots.try_into().unwrap(),
-            colors,
+            slots,
+            mappings,
+            played: [None; C],
             damper: [false; C],
-            when_damper_released: when_damper_released.try_into().unwrap()
+            when_damper_released: std::array::from_fn(|_| HashMap::new()),
+            eviction: Eviction::Damper,
+            hidden: std::array::from_fn(|_| HashSet::new()),
+            max_hold: None,
+            blink: None,
+            created: Instant::now()
         }
     }
 
-    pub fn draw(&self, canvas: &mut LedCanvas, first_column: i32) {
-        for s in 0..N {
+    const STUCK_WARNING: Duration = Duration::from_secs(5);
+    const BLINK_MS: u128 = 250;
+    const OVERFLOW: LedColor = LedColor { red: 64, green: 64, blue: 64 };
+
+    pub fn draw(&self, canvas: &mut dyn Canvas, first_column: i32, colors: &[LedColor; C]) {
+        for s in 0..self.slots.len() {
             if let Some(slot) = &self.slots[s] {
-                slot.draw(canvas, first_column + s as i32, self.colors);
+                // stuck notes blink before being cleared
+                let hidden = std::array::from_fn(|c| self.blink == Some(false) && self.is_stuck(slot, c));
+                slot.draw(canvas, first_column + s as i32, colors, hidden);
             }
         }
+        for s in 0..self.slots.len() {
+            if let Some(slot) = &self.slots[s] {
+                let hidden = std::array::from_fn(|c| self.blink == Some(false) && self.is_stuck(slot, c));
+                slot.draw_expression(canvas, first_column + s as i32, colors, hidden);
+            }
+        }
+        // light the columns either side when there are more notes sounding than slots to show them
+        if self.overflowing() {
+            let height = canvas.size().1;
+            for x in [first_column - 1, first_column + self.slots.len() as i32] {
+                canvas.draw_line(x, 0, x, height - 1, &Self::OVERFLOW);
+            }
+        }
+    }
+
+    pub fn set_eviction(&mut self, eviction: Eviction) {
+        self.eviction = eviction;
+    }
+
+    #[cfg(test)]
+    fn set_struck(&mut self, n: Note, struck: Instant) {
+        if let Some(slot) = self.slots.iter_mut().flatten().find(|slot| slot.note == n) {
+            slot.struck = struck;
+        }
+    }
+
+    // true if any notes are sounding without being shown, because all the slots were full
+    pub fn overflowing(&self) -> bool {
+        self.hidden.iter().any(|h| !h.is_empty())
     }
 
-    pub fn set_channel(&mut self, ch: Channel, v: Velocity) {
-        let c = ch.index() as usize;
+    // set the expression of a note which is sounding on the channel
+    pub fn set_expression(&mut self, n: Note, c: usize, expression: Expression) {
         if c < C {
-            for s in 0..N {
+            if let Some(slot) = self.slots.iter_mut().flatten().find(|slot| slot.note == n && slot.channels[c] > U7::MIN) {
+                slot.expression[c] = expression;
+            }
+        }
+    }
+
+    // notes held for longer than this are treated as stuck (eg. a lost NoteOff), so blink and are then cleared
+    pub fn set_max_hold(&mut self, max_hold: Option<Duration>) {
+        self.max_hold = max_hold;
+    }
+
+    fn is_stuck(&self, slot: &NoteSlot<C>, c: usize) -> bool {
+        self.max_hold.is_some_and(|max| slot.held(c).is_some_and(|held| held >= max))
+    }
+
+    // clear notes which have been stuck for long enough, returning true if that (or the blinking) changed anything
+    pub fn animate(&mut self) -> bool {
+        let Some(max_hold) = self.max_hold else {
+            return false;
+        };
+        let mut changed = false;
+        for s in 0..self.slots.len() {
+            let mut delete = false;
+            if let Some(slot) = &mut self.slots[s] {
+                for c in 0..C {
+                    if slot.held(c).is_some_and(|held| held >= max_hold + Self::STUCK_WARNING) {
+                        log_warn!("Clearing stuck note {} on channel {}", slot.note, c + 1);
+                        slot.set(c, U7::MIN, false);
+                        self.when_damper_released[c].remove(&slot.note);
+                        self.hidden[c].remove(&slot.note);
+                        changed = true;
+                    }
+                }
+                delete = slot.is_empty();
+            }
+            if delete {
+                self.slots[s] = None;
+            }
+        }
+        let stuck = self.slots().any(|slot| (0..C).any(|c| self.is_stuck(slot, c)));
+        let blink = stuck.then(|| (self.created.elapsed().as_millis() / Self::BLINK_MS).is_multiple_of(2));
+        changed |= blink != self.blink;
+        self.blink = blink;
+        changed
+    }
+
+    // time until a note becomes stuck, or the stuck notes next blink
+    pub fn next_animation(&self) -> Option<Duration> {
+        let max_hold = self.max_hold?;
+        if self.blink.is_some() {
+            let ms = Self::BLINK_MS - self.created.elapsed().as_millis() % Self::BLINK_MS;
+            return Some(Duration::from_millis(ms as u64));
+        }
+        self.slots()
+            .flat_map(|slot| (0..C).filter_map(|c| slot.held(c)))
+            .map(|held| max_hold.saturating_sub(held))
+            .min()
+    }
+
+    // notes already shown stay where they are, only new notes use the new mappings
+    pub fn set_mappings(&mut self, mappings: [NoteMapping; C]) {
+        self.mappings = mappings;
+    }
+
+    pub fn slots(&self) -> impl Iterator<Item = &NoteSlot<C>> {
+        self.slots.iter().flatten()
+    }
+
+    // set the aftertouch pressure of every note sounding on the channel, without changing how hard they were struck
+    pub fn set_channel_pressure(&mut self, c: usize, v: U7) {
+        if c < C {
+            for slot in self.slots.iter_mut().flatten() {
+                slot.set_pressure(c, v, false);
+            }
+        }
+    }
+
+    // set the aftertouch pressure of a note sounding on the channel (pressure never ends a note, even at 0)
+    pub fn set_pressure(&mut self, n: Note, c: usize, v: U7) {
+        if c < C {
+            if let Some(slot) = self.slots.iter_mut().flatten().find(|slot| slot.note == n) {
+                slot.set_pressure(c, v, true);
+            }
+        }
+    }
+
+    // release every note on the channel, as if each key was let go (so notes held by the damper stay until it is released)
+    pub fn release_channel(&mut self, c: usize) {
+        if c < C {
+            let mut held: Vec<Note> = self.slots().filter(|slot| slot.channels[c] > U7::MIN).map(|slot| slot.note).collect();
+            held.extend(self.hidden[c].iter());
+            for n in held {
+                self.set_note(n, c, U7::MIN);
+            }
+        }
+    }
+
+    // remove every note on the channel immediately, including those held by the damper
+    pub fn clear_channel(&mut self, c: usize) {
+        if c < C {
+            self.when_damper_released[c].clear();
+            self.hidden[c].clear();
+            for s in 0..self.slots.len() {
                 let mut delete = false;
                 if let Some(slot) = &mut self.slots[s] {
-                    if slot.channels[c] > U7::MIN {
-                        slot.channels[c] = v;
-                        delete = slot.is_empty();
-                    }
+                    slot.set(c, U7::MIN, false);
+                    delete = slot.is_empty();
                 }
                 if delete {
                     self.slots[s] = None;
@@ -120,25 +386,26 @@ impl<'a, const N: usize, const C: usize> NoteSlots<'a, N, C> {
         }
     }
 
-    pub fn set_damper(&mut self, ch: Channel, damper: bool) {
-        let c = ch.index() as usize;
+    pub fn set_damper(&mut self, c: usize, damper: bool) {
         if c < C {
             self.damper[c] = damper;
             if !damper {
                 let vec: Vec<_> = self.when_damper_released[c].drain().collect();
                 for (n, v) in vec {
-                    self.process_note(n, c, v, false);
+                    self.process_note(n, c, v, false, false);
                 }
             }
         }
     }
 
-    const MIN_NOTE: Note = Note::A0;
-    const MAX_NOTE: Note = Note::C8;
-
-    pub fn set_note(&mut self, n: Note, ch: Channel, v: Velocity) {
-        let c = ch.index() as usize;
-        if c < C && n >= Self::MIN_NOTE && n <= Self::MAX_NOTE {
+    pub fn set_note(&mut self, n: Note, c: usize, v: Velocity) {
+        if c < C && self.in_range(n, c) {
+            if self.mappings[c] == NoteMapping::Auto && v > U7::MIN {
+                self.played[c] = match self.played[c] {
+                    Some((lowest, highest)) => Some((lowest.min(n), highest.max(n))),
+                    None => Some((n, n))
+                };
+            }
             if self.damper[c] {
                 if v == U7::MIN {
                     // delay note off until damper released
@@ -147,16 +414,16 @@ impl<'a, const N: usize, const C: usize> NoteSlots<'a, N, C> {
                     // keep only the last press when damper released
                     self.when_damper_released[c].insert(n, v);
                     // but show the sum of them now
-                    self.process_note(n, c, v, true);
+                    self.process_note(n, c, v, true, true);
                 }
             } else {
                 // show the note as is
-                self.process_note(n, c, v, false);
+                self.process_note(n, c, v, false, true);
             }
         }
     }
 
-    fn process_note(&mut self, n: Note, c: usize, mut v: Velocity, sum_existing_v: bool) {
+    fn process_note(&mut self, n: Note, c: usize, mut v: Velocity, sum_existing_v: bool, restrike: bool) {
         let s = if let Some(existing) = self.find_slot(n) {
             // if note already exists, use that slot
             if sum_existing_v {
@@ -170,9 +437,18 @@ impl<'a, const N: usize, const C: usize> NoteSlots<'a, N, C> {
                 }
             }
             existing
+        } else if v == U7::MIN {
+            // releasing a note which isn't shown
+            self.hidden[c].remove(&n);
+            return;
         } else {
-            // find ideal slot by scaling all 88 piano notes into the number of slots
-            let ideal = (N * (n as usize - Self::MIN_NOTE as usize)) / (Self::MAX_NOTE as usize - Self::MIN_NOTE as usize + 1);
+            if self.slots.iter().all(|slot| slot.is_some()) && !self.evict() {
+                self.hidden[c].insert(n);
+                return;
+            }
+            self.hidden[c].remove(&n);
+            // find ideal slot by scaling the channel's range of notes into the number of slots
+            let ideal = self.ideal_slot(n, c);
             // move the ideal to be valid compared to other notes already existing
             let valid = self.valid_relative_to_existing(ideal, n);
             // create a slot for this note (moving others if nessesary)
@@ -181,15 +457,89 @@ impl<'a, const N: usize, const C: usize> NoteSlots<'a, N, C> {
             index
         };
         // update slot
-        self.slots[s].as_mut().unwrap().channels[c] = v;
+        self.slots[s].as_mut().unwrap().set(c, v, restrike);
         if self.slots[s].as_ref().unwrap().is_empty() {
             self.slots[s] = None;
         }
     }
 
+    // true if only the damper is holding the note, on every channel it is sounding on
+    fn is_sustained(&self, slot: &NoteSlot<C>) -> bool {
+        (0..C).filter(|c| slot.channels[*c] > U7::MIN).all(|c| self.when_damper_released[c].get(&slot.note) == Some(&U7::MIN))
+    }
+
+    // free a slot for a new note when they are all full, returning false if none can be
+    fn evict(&mut self) -> bool {
+        let slots: Vec<(usize, &NoteSlot<C>)> = self.slots.iter().enumerate().filter_map(|(s, slot)| slot.as_ref().map(|slot| (s, slot))).collect();
+        let oldest = |sustained_only: bool| slots.iter()
+            .filter(|(_, slot)| !sustained_only || self.is_sustained(slot))
+            .min_by_key(|(_, slot)| slot.struck)
+            .map(|(s, _)| *s);
+        let evicted = match self.eviction {
+            Eviction::Oldest => oldest(false),
+            Eviction::Quietest => slots.iter()
+                .min_by_key(|(_, slot)| (slot.channels.iter().map(|v| u8::from(*v) as usize).sum::<usize>(), slot.struck))
+                .map(|(s, _)| *s),
+            Eviction::Damper => oldest(true).or_else(|| oldest(false)),
+            Eviction::Never => oldest(true)
+        };
+        let Some(s) = evicted else {
+            return false;
+        };
+        // the evicted note is still sounding, so remember it until it is released
+        if let Some(slot) = self.slots[s].take() {
+            for c in (0..C).filter(|c| slot.channels[*c] > U7::MIN) {
+                self.hidden[c].insert(slot.note);
+            }
+        }
+        true
+    }
+
+    fn in_range(&self, n: Note, c: usize) -> bool {
+        match self.mappings[c] {
+            NoteMapping::Linear(lowest, highest) => n >= lowest && n <= highest,
+            NoteMapping::Auto => true,
+            NoteMapping::Fixed(lowest) => n >= lowest && (n as usize) < lowest as usize + self.slots.len()
+        }
+    }
+
+    fn ideal_slot(&self, n: Note, c: usize) -> usize {
+        self.scale(n, c, self.slots.len())
+    }
+
+    // find the position of a note within a given size, based on how the channel maps notes into slots
+    pub fn position(&self, n: Note, c: usize, size: usize) -> Option<usize> {
+        if c < C && self.in_range(n, c) {
+            Some(self.scale(n, c, size))
+        } else {
+            None
+        }
+    }
+
+    fn scale(&self, n: Note, c: usize, size: usize) -> usize {
+        let slots = self.slots.len();
+        let (lowest, highest) = match self.mappings[c] {
+            NoteMapping::Linear(lowest, highest) => (lowest as usize, highest as usize),
+            NoteMapping::Auto => {
+                let (lowest, highest) = self.played[c].unwrap_or((n, n));
+                let (lowest, highest) = (lowest as usize, highest as usize);
+                if highest - lowest + 1 < slots {
+                    // centre a range narrower than the slots, one slot per semitone
+                    let lowest = lowest.saturating_sub((slots - (highest - lowest + 1)) / 2);
+                    (lowest, lowest + slots - 1)
+                } else {
+                    (lowest, highest)
+                }
+            },
+            NoteMapping::Fixed(lowest) => (lowest as usize, lowest as usize + slots - 1)
+        };
+        let scaled = (size * (n as usize).saturating_sub(lowest)) / (highest - lowest + 1);
+        scaled.min(size - 1)
+    }
+
     fn valid_relative_to_existing(&self, ideal: usize, n: Note) -> usize {
         let mut valid = None;
-        for i in (ideal + 1)..N {
+        for i in (ideal + 1)..self.slots.len() {
             if let Some(slot) = &self.slots[i] {
                 if slot.note < n {
                     valid = Some(i);
@@ -217,7 +567,7 @@ impl<'a, const N: usize, const C: usize> NoteSlots<'a, N, C> {
     }
 
     fn find_slot(&mut self, n: Note) -> Option<usize> {
-        for s in 0..N {
+        for s in 0..self.slots.len() {
             if let Some(existing) = &mut self.slots[s] {
                 if existing.note == n {
                     return Some(s);
@@ -231,16 +581,16 @@ impl<'a, const N: usize, const C: usize> NoteSlots<'a, N, C> {
         if let Some(existing) = &self.slots[ideal] {
             if n > existing.note {
                 // we need to move up
-                if ideal == N - 1 || previous == Direction::Down {
+                if ideal == self.slots.len() - 1 || previous == Direction::Down {
                     // put it here
                     if self.shift_down(ideal) {
                         // shifted others down
                         ideal
-                    } else if ideal < N - 1 && self.shift_up(ideal + 1) {
+                    } else if ideal < self.slots.len() - 1 && self.shift_up(ideal + 1) {
                         // shifted others up
                         ideal + 1
                     } else {
-                        // slots full, therefore overwrite
+                        // slots full (not reached, as a slot is evicted first), therefore overwrite
                         ideal
                     }
                 } else {
@@ -258,7 +608,7 @@ impl<'a, const N: usize, const C: usize> NoteSlots<'a, N, C> {
                         // shifted others down
                         ideal - 1
                     } else {
-                        // slots full, therefore overwrite
+                        // slots full (not reached, as a slot is evicted first), therefore overwrite
                         ideal
                     }
                 } else {
@@ -276,7 +626,7 @@ impl<'a, const N: usize, const C: usize> NoteSlots<'a, N, C> {
 
     fn shift_up(&mut self, lower: usize) -> bool {
         let mut gap = None;
-        for s in lower..N {
+        for s in lower..self.slots.len() {
             if self.slots[s].is_none() {
                 gap = Some(s);
                 break;
@@ -305,4 +655,178 @@ impl<'a, const N: usize, const C: usize> NoteSlots<'a, N, C> {
             false
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLOTS: usize = 8;
+
+    fn slots(eviction: Eviction) -> NoteSlots<1> {
+        let mut notes = NoteSlots::new([NoteMapping::PIANO], SLOTS);
+        notes.set_eviction(eviction);
+        notes
+    }
+
+    fn play(notes: &mut NoteSlots<1>, n: u8, v: u8) {
+        notes.set_note(Note::from_u8_lossy(n), 0, U7::from_u8_lossy(v));
+    }
+
+    fn shown(notes: &NoteSlots<1>) -> Vec<u8> {
+        notes.slots().map(|slot| slot.note as u8).collect()
+    }
+
+    // every note shown once, in order of pitch from left to right
+    fn assert_ordered(notes: &NoteSlots<1>) {
+        let shown = shown(notes);
+        assert!(shown.windows(2).all(|w| w[0] < w[1]), "slots not ordered by pitch: {:?}", shown);
+    }
+
+    #[test]
+    fn sustained_dense_chords_stay_ordered() {
+        for eviction in Eviction::ALL {
+            let mut notes = slots(eviction);
+            notes.set_damper(0, true);
+            // clusters far more than the slots, struck across and inside each other
+            for root in [48, 72, 60, 36, 84, 66, 54] {
+                for n in (root..root + 12).step_by(2).chain((root + 1..root + 12).step_by(2)) {
+                    play(&mut notes, n, 100);
+                    assert_ordered(&notes);
+                }
+                for n in root..root + 12 {
+                    play(&mut notes, n, 0);
+                    assert_ordered(&notes);
+                }
+            }
+            assert_eq!(shown(&notes).len(), SLOTS, "{:?}", eviction);
+            notes.set_damper(0, false);
+            assert!(shown(&notes).is_empty(), "{:?}", eviction);
+            assert!(!notes.overflowing(), "{:?}", eviction);
+        }
+    }
+
+    #[test]
+    fn glissandi_stay_ordered() {
+        for eviction in Eviction::ALL {
+            for damper in [false, true] {
+                let mut notes = slots(eviction);
+                notes.set_damper(0, damper);
+                let up = 21..=108;
+                for n in up.clone().chain(up.clone().rev()).chain(up.step_by(3)) {
+                    play(&mut notes, n, 100);
+                    assert_ordered(&notes);
+                    play(&mut notes, n, 0);
+                    assert_ordered(&notes);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn held_glissando_stays_ordered() {
+        for eviction in Eviction::ALL {
+            let mut notes = slots(eviction);
+            // keys held down in both directions from the middle, eg. a palm across the keyboard
+            for (up, down) in (60..84).zip((36..60).rev()) {
+                play(&mut notes, up, 100);
+                assert_ordered(&notes);
+                play(&mut notes, down, 100);
+                assert_ordered(&notes);
+            }
+            assert_eq!(shown(&notes).len(), SLOTS, "{:?}", eviction);
+        }
+    }
+
+    #[test]
+    fn releasing_damper_keeps_hold_time() {
+        let mut notes = slots(Eviction::Damper);
+        notes.set_damper(0, true);
+        play(&mut notes, 60, 100);
+        let held = notes.slots().next().unwrap().held[0];
+        // the key is still down as the damper is released, so the note is re-applied
+        notes.set_damper(0, false);
+        assert_eq!(notes.slots().next().unwrap().held[0], held);
+        assert_eq!(shown(&notes), vec![60]);
+    }
+
+    #[test]
+    fn channel_pressure_keeps_hold_time() {
+        let mut notes = slots(Eviction::Damper);
+        play(&mut notes, 60, 100);
+        let held = notes.slots().next().unwrap().held[0];
+        // pressing another key could be sending the channel pressure, so the note may still be stuck
+        notes.set_channel_pressure(0, U7::MAX);
+        assert_eq!(notes.slots().next().unwrap().held[0], held);
+    }
+
+    #[test]
+    fn releasing_damper_keeps_oldest_note() {
+        let mut notes = slots(Eviction::Oldest);
+        notes.set_damper(0, true);
+        let start = Instant::now();
+        for (i, n) in (60..60 + SLOTS as u8).enumerate() {
+            play(&mut notes, n, 100);
+            notes.set_struck(Note::from_u8_lossy(n), start + Duration::from_millis(i as u64));
+        }
+        // every key is still down, so the first struck is still the oldest
+        notes.set_damper(0, false);
+        play(&mut notes, 80, 100);
+        assert!(!shown(&notes).contains(&60), "{:?}", shown(&notes));
+        assert!(shown(&notes).contains(&61));
+    }
+
+    #[test]
+    fn never_evicts_held_notes() {
+        let mut notes = slots(Eviction::Never);
+        for n in 60..60 + SLOTS as u8 {
+            play(&mut notes, n, 100);
+        }
+        play(&mut notes, 40, 100);
+        assert_eq!(shown(&notes), (60..60 + SLOTS as u8).collect::<Vec<_>>());
+        assert!(notes.overflowing());
+        // releasing the note which wasn't shown doesn't take a slot
+        play(&mut notes, 40, 0);
+        assert_eq!(shown(&notes).len(), SLOTS);
+        assert!(!notes.overflowing());
+    }
+
+    #[test]
+    fn damper_evicts_sustained_notes_first() {
+        for (eviction, evicted) in [(Eviction::Damper, 70), (Eviction::Never, 70), (Eviction::Oldest, 60)] {
+            let mut notes = slots(eviction);
+            let start = Instant::now();
+            notes.set_damper(0, true);
+            for (i, n) in (60..67).enumerate() {
+                play(&mut notes, n, 100);
+                notes.set_struck(Note::from_u8_lossy(n), start + Duration::from_millis(i as u64));
+            }
+            // the newest note, but only held by the damper
+            play(&mut notes, 70, 100);
+            notes.set_struck(Note::from_u8_lossy(70), start + Duration::from_millis(10));
+            play(&mut notes, 70, 0);
+            play(&mut notes, 80, 100);
+            assert!(!shown(&notes).contains(&evicted), "{:?} should evict {}: {:?}", eviction, evicted, shown(&notes));
+            assert!(shown(&notes).contains(&80));
+            assert_ordered(&notes);
+            assert!(notes.overflowing());
+        }
+    }
+
+    #[test]
+    fn quietest_evicts_softest_note() {
+        let mut notes = slots(Eviction::Quietest);
+        for n in 60..60 + SLOTS as u8 {
+            play(&mut notes, n, if n == 63 { 10 } else { 100 });
+        }
+        play(&mut notes, 50, 100);
+        assert!(!shown(&notes).contains(&63));
+        assert!(shown(&notes).contains(&50));
+        assert_ordered(&notes);
+        // the evicted note is still sounding until released
+        assert!(notes.overflowing());
+        play(&mut notes, 63, 0);
+        assert!(!notes.overflowing());
+        assert_eq!(shown(&notes).len(), SLOTS);
+    }
+}
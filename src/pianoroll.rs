@@ -0,0 +1,104 @@
+use std::array;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use rpi_led_matrix::LedColor;
+
+use crate::helper::add_assign;
+use crate::display::Canvas;
+
+pub struct PianoRoll<const C: usize> {
+    columns: VecDeque<[u64; C]>,
+    current: [u64; C],
+    // the notes which were sounding at the last step
+    carried: [u64; C],
+    width: usize,
+    rows: usize,
+    last_step: Instant
+}
+
+impl<const C: usize> PianoRoll<C> {
+    const MAX_ROWS: usize = 64;
+    const STEP_MS: u128 = 125; // so the history shown is the number of slots / 8 seconds
+
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            columns: VecDeque::new(),
+            current: [0; C],
+            carried: [0; C],
+            width,
+            rows: height.min(Self::MAX_ROWS),
+            last_step: Instant::now()
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn add(&mut self, row: usize, c: usize) {
+        if c < C && row < self.rows {
+            self.current[c] |= 1 << row;
+        }
+    }
+
+    // true if there's nothing to scroll
+    pub fn is_empty(&self) -> bool {
+        self.current.iter().chain(self.columns.iter().flatten()).all(|rows| *rows == 0)
+    }
+
+    // move on a column for each step which has passed, catching up if it hasn't been stepped while hidden,
+    // returning true if that changed what is shown
+    pub fn step(&mut self, sounding: impl Iterator<Item = (usize, usize)>) -> bool {
+        let steps = (self.last_step.elapsed().as_millis() / Self::STEP_MS) as usize;
+        if steps == 0 {
+            return false;
+        }
+        self.last_step = Instant::now();
+        let was_empty = self.is_empty();
+        // notes still sounding carry on into the next column
+        let mut sounding_column = [0; C];
+        for (row, c) in sounding {
+            if c < C && row < self.rows {
+                sounding_column[c] |= 1 << row;
+            }
+        }
+        // when catching up, notes played while hidden show in the latest column,
+        // with any notes sounding both before and now filling the gap
+        let held: [u64; C] = array::from_fn(|c| self.carried[c] & sounding_column[c]);
+        for _ in 1..steps.min(self.width) {
+            self.columns.push_front(held);
+        }
+        self.columns.push_front(self.current);
+        self.columns.truncate(self.width - 1);
+        self.current = sounding_column;
+        self.carried = sounding_column;
+        // only changed if there was or is something to see
+        !(was_empty && self.is_empty())
+    }
+
+    pub fn next_step(&self) -> Duration {
+        Duration::from_millis(Self::STEP_MS as u64).saturating_sub(self.last_step.elapsed())
+    }
+
+    pub fn draw(&self, canvas: &mut dyn Canvas, first_column: i32, colors: &[LedColor; C]) {
+        // current column on the right, with older columns scrolling left
+        let last_column = first_column + self.width as i32 - 1;
+        self.draw_column(canvas, last_column, &self.current, colors);
+        for (age, column) in self.columns.iter().enumerate() {
+            self.draw_column(canvas, last_column - 1 - age as i32, column, colors);
+        }
+    }
+
+    fn draw_column(&self, canvas: &mut dyn Canvas, x: i32, column: &[u64; C], colors: &[LedColor; C]) {
+        let bottom = canvas.size().1 - 1;
+        for row in 0..self.rows {
+            let mut color = LedColor { red: 0, green: 0, blue: 0 };
+            for (bits, channel_color) in column.iter().zip(colors) {
+                if bits & (1 << row) != 0 {
+                    add_assign(&mut color, channel_color);
+                }
+            }
+            canvas.set(x, bottom - row as i32, &color);
+        }
+    }
+}
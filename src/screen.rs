@@ -0,0 +1,51 @@
+use wmidi::{Channel, ControlFunction, MidiMessage, U7};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Screen {
+    Meter, Chords, PianoRoll, Clock, Bpm
+}
+
+impl Screen {
+    pub const ALL: [Screen; 5] = [Screen::Meter, Screen::Chords, Screen::PianoRoll, Screen::Clock, Screen::Bpm];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Screen::Meter => "meter",
+            Screen::Chords => "chords",
+            Screen::PianoRoll => "piano_roll",
+            Screen::Clock => "clock",
+            Screen::Bpm => "bpm"
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScreenTrigger {
+    /// CC (on a specific channel, or any channel if None) where the value selects the screen
+    ControlChange(Option<Channel>, ControlFunction),
+    /// Program Change (on a specific channel, or any channel if None) where the program number selects the screen
+    ProgramChange(Option<Channel>),
+    /// SysEx starting with these bytes, where the next byte selects the screen
+    SysEx(Vec<U7>)
+}
+
+impl ScreenTrigger {
+    // if the message matches this trigger, return the index of the screen selected
+    pub fn selects(&self, message: &MidiMessage) -> Option<usize> {
+        let index: U7 = match (self, message) {
+            (ScreenTrigger::ControlChange(channel, cc), MidiMessage::ControlChange(ch, message_cc, v)) if cc == message_cc && Self::on_channel(channel, ch) => *v,
+            (ScreenTrigger::ProgramChange(channel), MidiMessage::ProgramChange(ch, p)) if Self::on_channel(channel, ch) => *p,
+            (ScreenTrigger::SysEx(prefix), MidiMessage::OwnedSysEx(bytes)) if bytes.len() == prefix.len() + 1 && bytes.starts_with(prefix) => bytes[prefix.len()],
+            _ => return None
+        };
+        let index: u8 = index.into();
+        Some(index as usize)
+    }
+
+    fn on_channel(channel: &Option<Channel>, ch: &Channel) -> bool {
+        match channel {
+            Some(c) => c == ch,
+            None => true
+        }
+    }
+}
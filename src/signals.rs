@@ -0,0 +1,39 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const SIGHUP: i32 = 1;
+const SIGINT: i32 = 2;
+const SIGTERM: i32 = 15;
+
+extern "C" {
+    fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+}
+
+static TERMINATING: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signum: i32) {
+    // only async-signal-safe work is allowed here, so just flag it for the loops to check
+    match signum {
+        SIGHUP => RELOAD.store(true, Ordering::SeqCst),
+        _ => TERMINATING.store(true, Ordering::SeqCst)
+    }
+}
+
+// catch SIGTERM (from systemd) and SIGINT (from Ctrl+C) so the panel can be blanked before exiting,
+// and SIGHUP to reload the config
+pub fn handle() {
+    unsafe {
+        signal(SIGTERM, on_signal);
+        signal(SIGINT, on_signal);
+        signal(SIGHUP, on_signal);
+    }
+}
+
+pub fn terminating() -> bool {
+    TERMINATING.load(Ordering::SeqCst)
+}
+
+// true (once) if a reload has been requested since the last call
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::SeqCst)
+}
@@ -0,0 +1,67 @@
+use std::time::Duration;
+use rpi_led_matrix::LedColor;
+use wmidi::MidiMessage;
+
+// SysEx control protocol: F0 7D 4D 50 COMMAND DATA... F7
+// (7D is the manufacturer ID for non-commercial use, followed by "MP" for midi-panel-meter)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// 01 SECONDS TEXT...: show ASCII text over the meter for the given seconds (0 until replaced), or clear it if there is no text
+    Text(Option<Duration>, String),
+    /// 02 INDEX: show the screen at this position in the configured screens (counting from 0)
+    Screen(usize),
+    /// 03 CHANNEL RED GREEN BLUE: set the colour of a meter channel (counting from 0), with each component 0-127
+    Color(usize, LedColor),
+    /// 04 BRIGHTNESS: set the brightness percentage (0-100)
+    Brightness(u8),
+    /// 05: reply with 05 followed by the version in ASCII
+    Version
+}
+
+impl Command {
+    pub const PREFIX: [u8; 3] = [0x7D, 0x4D, 0x50];
+    const TEXT: u8 = 0x01;
+    const SCREEN: u8 = 0x02;
+    const COLOR: u8 = 0x03;
+    const BRIGHTNESS: u8 = 0x04;
+    const VERSION: u8 = 0x05;
+
+    pub fn parse(message: &MidiMessage) -> Option<Self> {
+        let MidiMessage::OwnedSysEx(data) = message else {
+            return None;
+        };
+        let bytes: Vec<u8> = data.iter().map(|b| (*b).into()).collect();
+        let command = bytes.strip_prefix(Self::PREFIX.as_slice())?;
+        Some(match command {
+            [Self::TEXT, seconds, text @ ..] => {
+                let duration = if *seconds == 0 { None } else { Some(Duration::from_secs(*seconds as u64)) };
+                let text = text.iter().filter(|b| b.is_ascii_graphic() || **b == b' ').map(|b| *b as char).collect();
+                Command::Text(duration, text)
+            },
+            [Self::SCREEN, index] => Command::Screen(*index as usize),
+            [Self::COLOR, channel, red, green, blue] => Command::Color(*channel as usize, LedColor {
+                red: Self::scale(*red),
+                green: Self::scale(*green),
+                blue: Self::scale(*blue)
+            }),
+            [Self::BRIGHTNESS, brightness] => Command::Brightness((*brightness).min(100)),
+            [Self::VERSION] => Command::Version,
+            _ => return None
+        })
+    }
+
+    // scale a 7 bit value to 8 bits, so that 127 is full brightness
+    fn scale(value: u8) -> u8 {
+        value << 1 | value >> 6
+    }
+
+    // the complete SysEx message (including F0 and F7) in reply to a version query
+    pub fn version_reply() -> Vec<u8> {
+        let mut reply = vec![0xF0];
+        reply.extend(Self::PREFIX);
+        reply.push(Self::VERSION);
+        reply.extend(env!("CARGO_PKG_VERSION").bytes());
+        reply.push(0xF7);
+        reply
+    }
+}
@@ -0,0 +1,44 @@
+use std::env;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::log::log_warn;
+
+static WATCHDOG: OnceLock<Option<Duration>> = OnceLock::new();
+static LAST_PING: Mutex<Option<Instant>> = Mutex::new(None);
+
+// send a state to the service manager (eg. "READY=1"), if running as a systemd notify service
+pub fn notify(state: &str) {
+    let Ok(path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(&path)
+    };
+    let result = addr.and_then(|addr| {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(state.as_bytes(), &addr)
+    });
+    if let Err(err) = result {
+        log_warn!("Cannot notify systemd of '{}': {}", state, err);
+    }
+}
+
+// tell the service manager we are still running, if it has a watchdog (pinging at half the timeout)
+pub fn watchdog() {
+    let interval = WATCHDOG.get_or_init(|| {
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        Some(Duration::from_micros(usec / 2))
+    });
+    if let Some(interval) = interval {
+        if let Ok(mut last) = LAST_PING.lock() {
+            if last.is_none_or(|l| l.elapsed() >= *interval) {
+                notify("WATCHDOG=1");
+                *last = Some(Instant::now());
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+use crate::midi;
+
+pub struct Tempo {
+    last_tick: Option<Instant>,
+    tick_ms: Option<f64>,
+    pub tick: usize
+}
+
+impl Tempo {
+    const STOPPED_MS: u128 = 1000;
+    const SMOOTHING: f64 = 0.1;
+
+    pub fn new() -> Self {
+        Self {
+            last_tick: None,
+            tick_ms: None,
+            tick: 0
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant) {
+        self.tick = if self.tick == midi::TICKS_PER_BEAT {
+            0
+        } else {
+            self.tick + 1
+        };
+        if let Some(last) = self.last_tick {
+            let ms = now.duration_since(last).as_secs_f64() * 1000.0;
+            // smooth out jitter in when ticks arrive
+            self.tick_ms = Some(match self.tick_ms {
+                Some(average) => average + (ms - average) * Self::SMOOTHING,
+                None => ms
+            });
+        }
+        self.last_tick = Some(now);
+    }
+
+    // forget the tempo once clock ticks stop arriving, returning true if it changed
+    pub fn animate(&mut self) -> bool {
+        if let Some(last) = self.last_tick {
+            if last.elapsed().as_millis() > Self::STOPPED_MS {
+                self.last_tick = None;
+                self.tick_ms = None;
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn next_animation(&self) -> Option<Duration> {
+        self.last_tick.map(|last| Duration::from_millis(Self::STOPPED_MS as u64 + 1).saturating_sub(last.elapsed()))
+    }
+
+    pub fn bpm(&self) -> Option<f64> {
+        self.tick_ms.map(|ms| 60000.0 / (ms * midi::TICKS_PER_BEAT as f64))
+    }
+}
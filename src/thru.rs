@@ -0,0 +1,106 @@
+use std::time::{Duration, Instant};
+use wmidi::MidiMessage;
+
+use crate::config::ThruConfig;
+use crate::error::Result;
+use crate::log::{log_debug, log_info, log_warn};
+use crate::midi::{MidiDevice, MidiOutput};
+
+// echoes incoming MIDI to another device (eg. a sound module), so the panel can sit in the MIDI chain
+pub struct Thru {
+    config: ThruConfig,
+    output: Option<MidiOutput>,
+    last_attempt: Option<Instant>
+}
+
+impl Thru {
+    const RETRY_INTERVAL: Duration = Duration::from_millis(1000);
+
+    pub fn new(config: &ThruConfig) -> Self {
+        Self {
+            config: config.clone(),
+            output: None,
+            last_attempt: None
+        }
+    }
+
+    // apply a changed config, reopening the output if the device changed
+    pub fn reconfigure(&mut self, config: &ThruConfig) {
+        if config.device != self.config.device {
+            self.output = None;
+            self.last_attempt = None;
+        }
+        self.config = config.clone();
+    }
+
+    // the message as it should be passed on, if at all
+    fn filter(&self, message: &MidiMessage) -> Option<MidiMessage<'static>> {
+        let mut message = message.to_owned();
+        match message.channel() {
+            Some(ch) => {
+                if self.config.channels.as_ref().is_some_and(|c| !c.contains(&ch)) {
+                    return None;
+                }
+                if let Some((_, to)) = self.config.remap.iter().find(|(from, _)| *from == ch) {
+                    message.set_channel(*to);
+                }
+            },
+            None => match message {
+                MidiMessage::TimingClock | MidiMessage::Start | MidiMessage::Continue | MidiMessage::Stop | MidiMessage::SongPositionPointer(_) if !self.config.clock => return None,
+                MidiMessage::OwnedSysEx(_) | MidiMessage::SysEx(_) if !self.config.sysex => return None,
+                _ => { }
+            }
+        }
+        Some(message)
+    }
+
+    // open the device if it isn't already, and send the rest of any message it wasn't ready for
+    // (called regularly rather than for each message, so waiting for the device to be plugged in doesn't hold up the panel)
+    pub fn connect(&mut self) {
+        let Some(device) = &self.config.device else {
+            return;
+        };
+        if self.output.is_none() && self.last_attempt.is_none_or(|a| a.elapsed() >= Self::RETRY_INTERVAL) {
+            self.last_attempt = Some(Instant::now());
+            match Self::open(device) {
+                Ok(output) => self.output = output,
+                Err(err) => log_warn!("{}", err)
+            }
+        }
+        // finish any message the device wasn't ready for, even if nothing more arrives
+        if let Some(output) = &mut self.output {
+            if let Err(err) = output.flush() {
+                log_warn!("{}, reopening MIDI thru", err);
+                self.output = None;
+            }
+        }
+    }
+
+    pub fn send(&mut self, message: &MidiMessage) {
+        let Some(message) = self.filter(message) else {
+            return;
+        };
+        let Some(output) = &mut self.output else {
+            return;
+        };
+        match output.send(&message.to_vec()) {
+            Ok(true) => { },
+            Ok(false) => log_debug!("MIDI thru is busy, dropped {:?}", message),
+            Err(err) => {
+                log_warn!("{}, reopening MIDI thru", err);
+                self.output = None;
+            }
+        }
+    }
+
+    fn open(pattern: &str) -> Result<Option<MidiOutput>> {
+        match MidiDevice::find(Some(pattern))? {
+            Some(device) => {
+                let output = MidiOutput::open(&device.path)?;
+                log_info!("Sending MIDI thru to {} ({})", device.path, device.name);
+                Ok(Some(output))
+            },
+            None => Ok(None)
+        }
+    }
+}